@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+/// `Cache` controls the caches of the kernel.
+///
+/// Note that the page cache of the files opened already is not invalidated
+/// until they are opened again because `fuse` cannot notify the kernel of it
+/// (see `invalidate_on_unstable` to bypass it from the next open).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cache {
+    /// The files opened while the state is stable.
+    pub stable: FileCache,
+    /// The files opened while the state is unstable.
+    pub unstable: FileCache,
+    /// How long the kernel caches the attributes and the directory entries.
+    pub ttl: Duration,
+    /// The caches are bypassed once the state changes to unstable:
+    /// the files are opened with `direct_io` and the attributes and the entries are not cached.
+    pub invalidate_on_unstable: bool,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache {
+            stable: FileCache::default(),
+            unstable: FileCache::default(),
+            ttl: Duration::from_secs(1),
+            invalidate_on_unstable: false,
+        }
+    }
+}
+
+impl Cache {
+    /// Returns the flags replied to `open` and `create` in the state.
+    pub fn open_flags(&self, unstable: bool) -> u32 {
+        use fuse::consts::FOPEN_DIRECT_IO;
+
+        match (unstable, self.invalidate_on_unstable) {
+            (true, true) => FOPEN_DIRECT_IO,
+            (true, false) => self.unstable.open_flags(),
+            (false, _) => self.stable.open_flags(),
+        }
+    }
+
+    /// Returns how long the kernel caches the attributes and the entries replied in the state.
+    pub fn ttl(&self, unstable: bool) -> Duration {
+        if unstable && self.invalidate_on_unstable {
+            Duration::from_secs(0)
+        } else {
+            self.ttl
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileCache {
+    /// Every read and write bypasses the page cache (`FOPEN_DIRECT_IO`),
+    /// so that the throttling applies to the cached data as well.
+    pub direct_io: bool,
+    /// The page cache is kept when the file is opened (`FOPEN_KEEP_CACHE`).
+    /// Otherwise, it is invalidated on every open.
+    pub keep_cache: bool,
+}
+
+impl FileCache {
+    /// Returns the flags replied to `open` and `create`.
+    pub fn open_flags(&self) -> u32 {
+        use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+
+        let mut flags = 0;
+        if self.direct_io {
+            flags |= FOPEN_DIRECT_IO;
+        }
+        if self.keep_cache {
+            flags |= FOPEN_KEEP_CACHE;
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+
+    #[test]
+    fn test_open_flags() {
+        let mut cache = Cache {
+            stable: FileCache {
+                direct_io: false,
+                keep_cache: true,
+            },
+            unstable: FileCache {
+                direct_io: true,
+                keep_cache: true,
+            },
+            ..Default::default()
+        };
+        assert_eq!(FOPEN_KEEP_CACHE, cache.open_flags(false));
+        assert_eq!(FOPEN_DIRECT_IO | FOPEN_KEEP_CACHE, cache.open_flags(true));
+
+        // The page cache is neither used nor kept in the unstable state.
+        cache.invalidate_on_unstable = true;
+        assert_eq!(FOPEN_KEEP_CACHE, cache.open_flags(false));
+        assert_eq!(FOPEN_DIRECT_IO, cache.open_flags(true));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut cache = Cache::default();
+        assert_eq!(Duration::from_secs(1), cache.ttl(false));
+        assert_eq!(Duration::from_secs(1), cache.ttl(true));
+
+        cache.invalidate_on_unstable = true;
+        assert_eq!(Duration::from_secs(1), cache.ttl(false));
+        assert_eq!(Duration::from_secs(0), cache.ttl(true));
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::os::raw::c_int;
+
+/// `Capacity` gives the mountpoint a virtual size.
+///
+/// Writes which make the usage exceed `bytes` fail with `error`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capacity {
+    pub bytes: u64,
+    pub error: NoSpaceError,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoSpaceError {
+    NoSpace,
+    QuotaExceeded,
+}
+
+impl NoSpaceError {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            NoSpaceError::NoSpace => libc::ENOSPC,
+            NoSpaceError::QuotaExceeded => libc::EDQUOT,
+        }
+    }
+}
+
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}B ({:?})", self.bytes, self.error)
+    }
+}
+
+/// Parses a size like `512`, `512B`, `4KB`, `10GB`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.strip_suffix('B').unwrap_or(s);
+    let (n, scale) = match s.chars().last().ok_or("Invalid size")? {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        'T' => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+
+    let size = n.parse::<u64>().map_err(|e| e.to_string())?;
+    size.checked_mul(scale)
+        .ok_or_else(|| "overflow".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("B").is_err());
+        assert!(parse_size("KB").is_err());
+        assert!(parse_size("1XB").is_err());
+        assert_eq!(Ok(512), parse_size("512"));
+        assert_eq!(Ok(512), parse_size("512B"));
+        assert_eq!(Ok(4 << 10), parse_size("4KB"));
+        assert_eq!(Ok(4 << 20), parse_size("4MB"));
+        assert_eq!(Ok(10 << 30), parse_size("10GB"));
+        assert_eq!(Ok(1 << 40), parse_size("1TB"));
+    }
+}
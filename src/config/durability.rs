@@ -0,0 +1,61 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// `Durability` decides when the written data are synced to the original files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// The data are synced only on `fsync` and `release` as requested by the applications.
+    #[default]
+    PassThrough,
+    /// The data are synced on every write.
+    AlwaysSync,
+    /// The data are never synced (`fsync` reports success without syncing).
+    NeverSync,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass_through" => Ok(Durability::PassThrough),
+            "always_sync" => Ok(Durability::AlwaysSync),
+            "never_sync" => Ok(Durability::NeverSync),
+            _ => Err(format!("Invalid durability: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Durability::PassThrough => write!(f, "pass_through"),
+            Durability::AlwaysSync => write!(f, "always_sync"),
+            Durability::NeverSync => write!(f, "never_sync"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_durability_from_str() {
+        assert!(Durability::from_str("").is_err());
+        assert_eq!(Ok(Durability::PassThrough), "pass_through".parse());
+        assert_eq!(Ok(Durability::AlwaysSync), "always_sync".parse());
+        assert_eq!(Ok(Durability::NeverSync), "never_sync".parse());
+    }
+
+    #[test]
+    fn test_durability_to_string() {
+        for durability in &[
+            Durability::PassThrough,
+            Durability::AlwaysSync,
+            Durability::NeverSync,
+        ] {
+            assert_eq!(Ok(*durability), durability.to_string().parse());
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// `Faults` are injected into the operations while the state is unstable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    pub fsync: Option<FsyncFault>,
+    /// All the operations modifying files fail with `EROFS`
+    /// (like ext4 remounted as read-only after errors).
+    pub read_only: bool,
+    /// All the operations fail as if the device disappeared (e.g., USB or iSCSI).
+    /// The files opened before the disappearance cannot be used even after the device returns.
+    pub disappear: Option<DisappearError>,
+    pub truncate: Option<TruncateFault>,
+    pub xattr: Option<XattrFault>,
+    pub lock: Option<LockFault>,
+    /// `access` fails with `EACCES` for these paths (relative to the mountpoint) and the files under them.
+    pub deny_access: Vec<PathBuf>,
+    pub timestamps: Option<TimestampFault>,
+    /// Each entry of the directory listings is delayed by the given duration.
+    pub readdir_delay: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsyncFault {
+    /// `fsync` fails with `EIO` once and the data written since the last `fsync` are dropped.
+    /// The retry succeeds as if nothing happened (like the page cache of Linux).
+    FailOnce,
+    /// `fsync` is delayed by the given duration.
+    Delay(Duration),
+    /// `fsync` reports success without syncing.
+    Lie,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TruncateFault {
+    /// Changing the file size fails with `EIO`.
+    IoError,
+    /// Changing the file size reports success without changing it.
+    Ignore,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum XattrFault {
+    /// Getting and removing extended attributes fail with `ENODATA`
+    /// and listing them returns nothing.
+    NoData,
+    /// All the operations on extended attributes fail with `ENOTSUP`.
+    NotSupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockFault {
+    /// Acquiring locks is delayed by the given duration.
+    Delay(Duration),
+    /// Acquiring locks fails with `EAGAIN` as if another process held them.
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimestampFault {
+    /// The timestamps of the files are reported as they were when the fault started
+    /// (e.g., mtime does not change on writes).
+    Stale,
+    /// The timestamps of the files are shifted by the given seconds (negative values go back to the past).
+    Skew(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisappearError {
+    IoError,
+    NotConnected,
+}
+
+impl XattrFault {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            XattrFault::NoData => libc::ENODATA,
+            XattrFault::NotSupported => libc::ENOTSUP,
+        }
+    }
+}
+
+impl DisappearError {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            DisappearError::IoError => libc::EIO,
+            DisappearError::NotConnected => libc::ENOTCONN,
+        }
+    }
+}
@@ -1,18 +1,46 @@
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::path::Path;
 
+pub use self::cache::Cache;
+pub use self::capacity::{parse_size, Capacity, NoSpaceError};
 pub use self::condition::Condition;
+pub use self::durability::Durability;
+pub use self::fault::{Faults, FsyncFault, LockFault, TimestampFault, TruncateFault, XattrFault};
+pub use self::mount::{MountOptions, MountSpec};
 pub use self::operation::Operation;
 pub use self::speed::Speed;
+pub use self::statfs::Statfs;
 
+mod cache;
+mod capacity;
 mod condition;
+mod durability;
+mod fault;
+mod mount;
 mod operation;
 mod speed;
+mod statfs;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub speed: Speed,
     pub operations: Vec<Operation>,
     pub condition: Condition,
+    #[serde(default)]
+    pub capacity: Option<Capacity>,
+    #[serde(default)]
+    pub max_files: Option<u64>,
+    #[serde(default)]
+    pub faults: Faults,
+    #[serde(default)]
+    pub statfs: Statfs,
+    #[serde(default)]
+    pub durability: Durability,
+    #[serde(default)]
+    pub cache: Cache,
 }
 
 impl Default for Config {
@@ -21,10 +49,36 @@ impl Default for Config {
             speed: Speed::PassThrough,
             operations: vec![Operation::Read, Operation::Write],
             condition: Condition::default_periodic(),
+            capacity: None,
+            max_files: None,
+            faults: Faults::default(),
+            statfs: Statfs::default(),
+            durability: Durability::default(),
+            cache: Cache::default(),
         }
     }
 }
 
+/// `ConfigFile` is the JSON file given by `--config`.
+/// `mount` and `config` are for the mount given on the command line and the options override them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub mount: MountOptions,
+    #[serde(default)]
+    pub config: Option<Config>,
+    /// The other mounts keyed by their names.
+    #[serde(default)]
+    pub mounts: BTreeMap<String, MountSpec>,
+}
+
+impl ConfigFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, Box<dyn Error>> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
 impl fmt::Display for Config {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let ops = self
@@ -33,10 +87,19 @@ impl fmt::Display for Config {
             .map(|x| x.to_string())
             .collect::<Vec<_>>()
             .join(":");
+        let capacity = self
+            .capacity
+            .as_ref()
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "unlimited".to_string());
+        let max_files = self
+            .max_files
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "unlimited".to_string());
         write!(
             fmt,
-            "config {{speed: {}, operations: {}, condition: {:?}}}",
-            ops, self.speed, self.condition
+            "config {{speed: {}, operations: {}, condition: {:?}, capacity: {}, max_files: {}, faults: {:?}, statfs: {:?}, durability: {}, cache: {:?}}}",
+            ops, self.speed, self.condition, capacity, max_files, self.faults, self.statfs, self.durability, self.cache
         )
     }
 }
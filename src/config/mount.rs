@@ -0,0 +1,148 @@
+use super::Config;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// `MountOptions` are passed to FUSE (see mount.fuse(8)).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MountOptions {
+    /// The users other than the one running mizumochi can access the mountpoint.
+    pub allow_other: bool,
+    /// The kernel checks the permissions of all the operations by the mode of the files.
+    pub default_permissions: bool,
+    pub read_only: bool,
+    pub fsname: Option<String>,
+    pub subtype: Option<String>,
+    /// The mountpoint is unmounted when mizumochi exits.
+    pub auto_unmount: bool,
+    pub max_read: Option<u32>,
+}
+
+/// `MountSpec` describes a mount managed by mizumochi.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountSpec {
+    pub original_dir: PathBuf,
+    pub mountpoint: PathBuf,
+    /// The changes are written to this directory instead of `original_dir` if it is given.
+    #[serde(default)]
+    pub upper_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub options: MountOptions,
+    /// The default config is used if it is not given.
+    #[serde(default)]
+    pub config: Option<Config>,
+}
+
+impl MountOptions {
+    /// Validates the options and returns the arguments passed to FUSE.
+    pub fn to_args(&self) -> Result<Vec<OsString>, String> {
+        let mut options = Vec::new();
+
+        if self.allow_other {
+            if !is_root() && !allows_other(&fs::read_to_string(FUSE_CONF).unwrap_or_default()) {
+                return Err(format!(
+                    "allow_other requires `user_allow_other` in {} unless mizumochi runs as root",
+                    FUSE_CONF
+                ));
+            }
+            options.push("allow_other".to_string());
+        }
+        if self.default_permissions {
+            options.push("default_permissions".to_string());
+        }
+        if self.read_only {
+            options.push("ro".to_string());
+        }
+        if let Some(ref fsname) = self.fsname {
+            options.push(format!("fsname={}", validate_value("fsname", fsname)?));
+        }
+        if let Some(ref subtype) = self.subtype {
+            options.push(format!("subtype={}", validate_value("subtype", subtype)?));
+        }
+        if self.auto_unmount {
+            options.push("auto_unmount".to_string());
+        }
+        match self.max_read {
+            Some(0) => return Err("max_read must be positive".to_string()),
+            Some(max_read) => options.push(format!("max_read={}", max_read)),
+            None => {}
+        }
+
+        if options.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Ok(vec!["-o".into(), options.join(",").into()])
+        }
+    }
+}
+
+fn validate_value<'a>(name: &str, value: &'a str) -> Result<&'a str, String> {
+    if value.is_empty() || value.contains(',') || value.contains('=') {
+        Err(format!("Invalid {}: {:?}", name, value))
+    } else {
+        Ok(value)
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns whether `user_allow_other` is enabled in the content of fuse.conf.
+fn allows_other(fuse_conf: &str) -> bool {
+    fuse_conf
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .any(|line| line == "user_allow_other")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_args() {
+        assert!(MountOptions::default().to_args().unwrap().is_empty());
+
+        let options = MountOptions {
+            default_permissions: true,
+            read_only: true,
+            fsname: Some("mizumochi".to_string()),
+            subtype: Some("slow".to_string()),
+            auto_unmount: true,
+            max_read: Some(4096),
+            ..Default::default()
+        };
+        let args: Vec<OsString> = vec![
+            "-o".into(),
+            "default_permissions,ro,fsname=mizumochi,subtype=slow,auto_unmount,max_read=4096"
+                .into(),
+        ];
+        assert_eq!(Ok(args), options.to_args());
+
+        let options = MountOptions {
+            fsname: Some("a,allow_other".to_string()),
+            ..Default::default()
+        };
+        assert!(options.to_args().is_err());
+
+        let options = MountOptions {
+            max_read: Some(0),
+            ..Default::default()
+        };
+        assert!(options.to_args().is_err());
+    }
+
+    #[test]
+    fn test_allows_other() {
+        assert!(!allows_other(""));
+        assert!(!allows_other("#user_allow_other\n"));
+        assert!(!allows_other("mount_max = 1000\n"));
+        assert!(allows_other(
+            "mount_max = 1000\nuser_allow_other  # comment\n"
+        ));
+    }
+}
@@ -0,0 +1,13 @@
+/// `Statfs` overrides or scales the numbers which `statfs` reports from the original directory.
+///
+/// The virtual capacity and the maximum number of files take precedence over them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Statfs {
+    pub total_bytes: Option<u64>,
+    pub free_bytes: Option<u64>,
+    pub total_files: Option<u64>,
+    pub free_files: Option<u64>,
+    /// Scales the free bytes and the free files (e.g., `0.05` reports only 5% of them).
+    pub free_ratio: Option<f64>,
+}
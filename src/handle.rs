@@ -0,0 +1,236 @@
+use crate::localfile::Inode;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::fs::FileExt;
+
+/// `Handle` is an opened file in the original directory.
+pub struct Handle {
+    pub ino: Inode,
+    pub file: File,
+
+    // The generation of the device when the file was opened.
+    generation: u64,
+
+    // The file size and the overwritten contents since the last fsync keyed by their offsets.
+    // These are kept to drop the written data on fsync failure.
+    // Only the first contents of each range are kept, so the journal never exceeds the synced file.
+    synced_len: Option<u64>,
+    journal: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Handle {
+    pub fn new(ino: Inode, file: File, generation: u64) -> Handle {
+        Handle {
+            ino,
+            file,
+            generation,
+            synced_len: None,
+            journal: BTreeMap::new(),
+        }
+    }
+
+    /// Returns `ESTALE` if the file was opened before the device disappeared.
+    pub fn ensure_generation(&self, generation: u64) -> Result<(), c_int> {
+        if self.generation == generation {
+            Ok(())
+        } else {
+            Err(libc::ESTALE)
+        }
+    }
+
+    /// Reads from `offset` until the buffer is filled or the end of the file.
+    /// The position of the file is not changed.
+    pub fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize, io::Error> {
+        let mut read_size = 0;
+        while read_size < buffer.len() {
+            match self
+                .file
+                .read_at(&mut buffer[read_size..], offset + read_size as u64)
+            {
+                Ok(0) => break,
+                Ok(size) => read_size += size,
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(read_size)
+    }
+
+    /// Writes the whole buffer at `offset`.
+    /// The position of the file is not changed.
+    pub fn write_at(&self, buffer: &[u8], offset: u64) -> Result<usize, io::Error> {
+        self.file.write_all_at(buffer, offset)?;
+        Ok(buffer.len())
+    }
+
+    /// Saves the contents which will be overwritten by writing `size` bytes at `offset`.
+    /// The ranges saved already and the ones beyond the synced file are skipped.
+    pub fn record(&mut self, offset: u64, size: usize) -> Result<(), io::Error> {
+        let synced_len = match self.synced_len {
+            Some(len) => len,
+            None => {
+                let len = self.file.metadata()?.len();
+                self.synced_len = Some(len);
+                len
+            }
+        };
+
+        let end = (offset + size as u64).min(synced_len);
+        if end <= offset {
+            return Ok(());
+        }
+        for (start, end) in self.unsaved_ranges(offset, end) {
+            let mut buffer = vec![0; (end - start) as usize];
+            let read_size = self.file.read_at(&mut buffer, start)?;
+            buffer.truncate(read_size);
+            if !buffer.is_empty() {
+                self.journal.insert(start, buffer);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the parts of `start..end` which are not saved in the journal.
+    fn unsaved_ranges(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut pos = start;
+        // The range saved before `start` may cover the beginning.
+        let before = self.journal.range(..start).next_back();
+        for (&saved_start, saved) in before.into_iter().chain(self.journal.range(start..end)) {
+            if pos < saved_start {
+                ranges.push((pos, saved_start));
+            }
+            pos = pos.max(saved_start + saved.len() as u64);
+        }
+        if pos < end {
+            ranges.push((pos, end));
+        }
+        ranges
+    }
+
+    /// Reverts the file to the contents at the last fsync.
+    /// Returns `false` if there is no written data to drop.
+    pub fn drop_unsynced(&mut self) -> Result<bool, io::Error> {
+        let synced_len = match self.synced_len.take() {
+            Some(len) => len,
+            None => return Ok(false),
+        };
+
+        for (offset, buffer) in mem::take(&mut self.journal) {
+            self.file.write_all_at(&buffer, offset)?;
+        }
+        self.file.set_len(synced_len)?;
+
+        Ok(true)
+    }
+
+    /// Forgets the saved contents since the written data have been synced.
+    pub fn clear_journal(&mut self) {
+        self.synced_len = None;
+        self.journal.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn test_drop_unsynced() {
+        let path = std::env::temp_dir().join(format!("mizumochi_handle_{}", std::process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut handle = Handle::new(2, file, 0);
+
+        // Nothing to drop.
+        assert!(!handle.drop_unsynced().unwrap());
+
+        handle.record(8, 4).unwrap();
+        handle.file.write_all_at(b"abcd", 8).unwrap();
+        handle.record(0, 2).unwrap();
+        handle.file.write_all_at(b"xy", 0).unwrap();
+        assert_eq!(b"xy234567abcd".to_vec(), fs::read(&path).unwrap());
+
+        assert!(handle.drop_unsynced().unwrap());
+        assert_eq!(b"0123456789".to_vec(), fs::read(&path).unwrap());
+        assert!(!handle.drop_unsynced().unwrap());
+
+        // The synced data are kept.
+        handle.record(0, 2).unwrap();
+        handle.file.write_all_at(b"xy", 0).unwrap();
+        handle.clear_journal();
+        assert!(!handle.drop_unsynced().unwrap());
+        assert_eq!(b"xy23456789".to_vec(), fs::read(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("mizumochi_journal_{}", std::process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut handle = Handle::new(2, file, 0);
+
+        // Overwriting the same range again keeps only the first contents.
+        for _ in 0..3 {
+            handle.record(2, 4).unwrap();
+            handle.file.write_all_at(b"abcd", 2).unwrap();
+        }
+        handle.record(4, 4).unwrap();
+        handle.file.write_all_at(b"wxyz", 4).unwrap();
+        // Appending saves nothing.
+        handle.record(10, 100).unwrap();
+        handle.file.write_all_at(&[b'!'; 100], 10).unwrap();
+        assert_eq!(
+            vec![(2, b"2345".to_vec()), (6, b"67".to_vec())],
+            handle.journal.clone().into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![(0, 2), (8, 10)], handle.unsaved_ranges(0, 10));
+
+        assert!(handle.drop_unsynced().unwrap());
+        assert_eq!(b"0123456789".to_vec(), fs::read(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_at() {
+        let path = std::env::temp_dir().join(format!("mizumochi_pio_{}", std::process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let handle = Handle::new(2, file, 0);
+
+        let mut buffer = [0; 4];
+        assert_eq!(4, handle.read_at(&mut buffer, 2).unwrap());
+        assert_eq!(b"2345", &buffer);
+        // Reading stops at the end of the file.
+        assert_eq!(2, handle.read_at(&mut buffer, 8).unwrap());
+        assert_eq!(0, handle.read_at(&mut buffer, 20).unwrap());
+
+        assert_eq!(3, handle.write_at(b"abc", 12).unwrap());
+        assert_eq!(b"0123456789\0\0abc".to_vec(), fs::read(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
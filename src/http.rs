@@ -1,5 +1,6 @@
-use crate::config::Config;
-use atomic_immut::AtomicImmut;
+use crate::config::{Config, MountSpec};
+use crate::mounts::{Mounts, DEFAULT_MOUNT};
+use crate::shutdown::Shutdown;
 use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
 use bytecodec::null::{NullDecoder, NullEncoder};
 use fibers::{Executor, InPlaceExecutor, Spawn};
@@ -9,55 +10,137 @@ use futures::future::ok;
 use futures::Future;
 use httpcodec::{BodyDecoder, BodyEncoder};
 use slog::Logger;
+use std::collections::BTreeMap;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
 
 pub fn start_server(
     logger: Logger,
     port: u16,
-    config: Arc<AtomicImmut<Config>>,
+    mounts: Mounts,
+    shutdown: Shutdown,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let executor = InPlaceExecutor::new()?;
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let mut builder = ServerBuilder::new(addr);
-    builder.add_handler(GetConfigHandler(Arc::clone(&config)))?;
-    builder.add_handler(PutConfigHandler { logger, config })?;
+    builder.add_handler(GetConfigHandler(mounts.clone()))?;
+    builder.add_handler(PutConfigHandler {
+        logger: logger.clone(),
+        mounts: mounts.clone(),
+    })?;
+    builder.add_handler(GetMountsHandler(mounts.clone()))?;
+    builder.add_handler(PutMountHandler {
+        logger: logger.clone(),
+        mounts: mounts.clone(),
+    })?;
+    builder.add_handler(DeleteMountHandler {
+        logger: logger.clone(),
+        mounts: mounts.clone(),
+    })?;
+    builder.add_handler(ResetHandler {
+        logger: logger.clone(),
+        mounts: mounts.clone(),
+    })?;
+    builder.add_handler(ResetMountHandler {
+        logger: logger.clone(),
+        mounts: mounts.clone(),
+    })?;
+    builder.add_handler(GetMountConfigHandler(mounts.clone()))?;
+    builder.add_handler(PutMountConfigHandler {
+        logger: logger.clone(),
+        mounts,
+    })?;
 
     // Enables process metrics and registers a HTTP endpoint for exporting metrics
     prometrics::default_registry().register(prometrics::metrics::ProcessMetricsCollector::new());
     builder.add_handler(MetricsHandler)?;
 
     // Starts HTTP server
+    // mizumochi shuts down if the server stops by an error.
     let http_server = builder.finish(executor.handle());
-    executor.spawn(http_server.map_err(|e| panic!("{}", e)));
+    let (server_logger, server_shutdown) = (logger.clone(), shutdown.clone());
+    executor.spawn(http_server.map_err(move |e| {
+        error!(server_logger, "HTTP server error: {}", e);
+        server_shutdown.fail();
+    }));
     std::thread::spawn(move || {
         if let Err(e) = executor.run() {
-            panic!("{}", e);
+            error!(logger, "HTTP server error: {}", e);
+            shutdown.fail();
         }
     });
     Ok(())
 }
 
-struct GetConfigHandler(Arc<AtomicImmut<Config>>);
+/// Returns the name of the mount in the path (`/mounts/{name}/...`).
+fn mount_name<T>(req: &Req<T>) -> String {
+    req.url()
+        .path_segments()
+        .and_then(|mut segments| segments.nth(1))
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn status_from(error: &io::Error) -> Status {
+    match error.kind() {
+        io::ErrorKind::InvalidInput => Status::BadRequest,
+        io::ErrorKind::NotFound => Status::NotFound,
+        io::ErrorKind::AlreadyExists | io::ErrorKind::ResourceBusy => Status::Conflict,
+        _ => Status::InternalServerError,
+    }
+}
+
+fn get_config(mounts: &Mounts, name: &str) -> Res<Option<Config>> {
+    match mounts.config(name) {
+        Some(config) => Res::new(Status::Ok, Some((*config.load()).clone())),
+        None => Res::new(Status::NotFound, None),
+    }
+}
+
+fn put_config(logger: &Logger, mounts: &Mounts, name: &str, config: Config) -> Res<()> {
+    match mounts.config(name) {
+        Some(current) => {
+            current.store(config.clone());
+            info!(logger, "new config: {:?}", config; "mount" => name);
+            Res::new(Status::Ok, ())
+        }
+        None => Res::new(Status::NotFound, ()),
+    }
+}
+
+fn reset(logger: &Logger, mounts: &Mounts, name: &str) -> Res<()> {
+    let status = match mounts.reset(name) {
+        // The mount is mounted again in the background.
+        Ok(()) => Status::Accepted,
+        Err(error) => {
+            error!(logger, "Cannot reset {}: {}", name, error);
+            status_from(&error)
+        }
+    };
+    Res::new(status, ())
+}
+
+/// `GetConfigHandler` returns the config of the mount given on the command line.
+struct GetConfigHandler(Mounts);
 impl HandleRequest for GetConfigHandler {
     const METHOD: &'static str = "GET";
     const PATH: &'static str = "/config";
 
     type ReqBody = ();
-    type ResBody = Config;
+    type ResBody = Option<Config>;
     type Decoder = BodyDecoder<NullDecoder>;
-    type Encoder = BodyEncoder<JsonEncoder<Config>>;
+    type Encoder = BodyEncoder<JsonEncoder<Option<Config>>>;
     type Reply = Reply<Self::ResBody>;
 
     fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
-        let config = (*self.0.load()).clone();
-        Box::new(ok(Res::new(Status::Ok, config)))
+        Box::new(ok(get_config(&self.0, DEFAULT_MOUNT)))
     }
 }
 
+/// `PutConfigHandler` modifies the config of the mount given on the command line.
 struct PutConfigHandler {
     logger: Logger,
-    config: Arc<AtomicImmut<Config>>,
+    mounts: Mounts,
 }
 impl HandleRequest for PutConfigHandler {
     const METHOD: &'static str = "PUT";
@@ -71,9 +154,157 @@ impl HandleRequest for PutConfigHandler {
 
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let config = req.into_body();
-        self.config.store(config.clone());
-        info!(self.logger, "new config: {:?}", config);
+        Box::new(ok(put_config(
+            &self.logger,
+            &self.mounts,
+            DEFAULT_MOUNT,
+            config,
+        )))
+    }
+}
+
+/// `ResetHandler` discards the changes of the mount given on the command line in the overlay mode.
+struct ResetHandler {
+    logger: Logger,
+    mounts: Mounts,
+}
+impl HandleRequest for ResetHandler {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/reset";
+
+    type ReqBody = ();
+    type ResBody = ();
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<NullEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        Box::new(ok(reset(&self.logger, &self.mounts, DEFAULT_MOUNT)))
+    }
+}
+
+struct GetMountsHandler(Mounts);
+impl HandleRequest for GetMountsHandler {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/mounts";
+
+    type ReqBody = ();
+    type ResBody = BTreeMap<String, MountSpec>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<BTreeMap<String, MountSpec>>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        Box::new(ok(Res::new(Status::Ok, self.0.specs())))
+    }
+}
+
+struct PutMountHandler {
+    logger: Logger,
+    mounts: Mounts,
+}
+impl HandleRequest for PutMountHandler {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/mounts/*";
+
+    type ReqBody = MountSpec;
+    type ResBody = ();
+    type Decoder = BodyDecoder<JsonDecoder<MountSpec>>;
+    type Encoder = BodyEncoder<NullEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let name = mount_name(&req);
+        let status = match self.mounts.add(&name, req.into_body()) {
+            Ok(()) => Status::Created,
+            Err(error) => {
+                error!(self.logger, "Cannot mount {}: {}", name, error);
+                status_from(&error)
+            }
+        };
+        Box::new(ok(Res::new(status, ())))
+    }
+}
+
+struct DeleteMountHandler {
+    logger: Logger,
+    mounts: Mounts,
+}
+impl HandleRequest for DeleteMountHandler {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/mounts/*";
+
+    type ReqBody = ();
+    type ResBody = ();
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<NullEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let name = mount_name(&req);
+        let status = match self.mounts.remove(&name) {
+            Ok(()) => Status::Ok,
+            Err(error) => {
+                error!(self.logger, "Cannot unmount {}: {}", name, error);
+                status_from(&error)
+            }
+        };
+        Box::new(ok(Res::new(status, ())))
+    }
+}
+
+struct GetMountConfigHandler(Mounts);
+impl HandleRequest for GetMountConfigHandler {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/mounts/*/config";
+
+    type ReqBody = ();
+    type ResBody = Option<Config>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Option<Config>>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        Box::new(ok(get_config(&self.0, &mount_name(&req))))
+    }
+}
+
+struct PutMountConfigHandler {
+    logger: Logger,
+    mounts: Mounts,
+}
+impl HandleRequest for PutMountConfigHandler {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/mounts/*/config";
+
+    type ReqBody = Config;
+    type ResBody = ();
+    type Decoder = BodyDecoder<JsonDecoder<Config>>;
+    type Encoder = BodyEncoder<NullEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let name = mount_name(&req);
+        let config = req.into_body();
+        Box::new(ok(put_config(&self.logger, &self.mounts, &name, config)))
+    }
+}
+
+struct ResetMountHandler {
+    logger: Logger,
+    mounts: Mounts,
+}
+impl HandleRequest for ResetMountHandler {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/mounts/*/reset";
+
+    type ReqBody = ();
+    type ResBody = ();
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<NullEncoder>;
+    type Reply = Reply<Self::ResBody>;
 
-        Box::new(ok(Res::new(Status::Ok, ())))
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        Box::new(ok(reset(&self.logger, &self.mounts, &mount_name(&req))))
     }
 }
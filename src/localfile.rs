@@ -1,3 +1,5 @@
+use fuse::FileType;
+use std::fs::Metadata;
 use std::path::PathBuf;
 
 pub type Inode = u64;
@@ -5,6 +7,86 @@ pub type Inode = u64;
 #[derive(Debug, Clone)]
 pub enum LocalFile {
     RegularFile(PathBuf),
+    Symlink(PathBuf),
+    // Named pipes, sockets and devices.
+    Special(PathBuf, FileType),
     // Note that the `PathBuf` in Vec<(Inode, PathBuf)> refers filename (not filepath).
     Directory(PathBuf, Option<Vec<(Inode, PathBuf)>>),
 }
+
+impl LocalFile {
+    /// Classifies the file by the metadata which does not follow the symbolic links.
+    /// The files in the directory are not fetched yet.
+    pub fn from_metadata(path: PathBuf, metadata: &Metadata) -> LocalFile {
+        use std::os::unix::fs::FileTypeExt;
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            LocalFile::Directory(path, None)
+        } else if file_type.is_symlink() {
+            LocalFile::Symlink(path)
+        } else if file_type.is_fifo() {
+            LocalFile::Special(path, FileType::NamedPipe)
+        } else if file_type.is_socket() {
+            LocalFile::Special(path, FileType::Socket)
+        } else if file_type.is_char_device() {
+            LocalFile::Special(path, FileType::CharDevice)
+        } else if file_type.is_block_device() {
+            LocalFile::Special(path, FileType::BlockDevice)
+        } else {
+            LocalFile::RegularFile(path)
+        }
+    }
+
+    pub fn kind(&self) -> FileType {
+        match self {
+            LocalFile::RegularFile(_) => FileType::RegularFile,
+            LocalFile::Symlink(_) => FileType::Symlink,
+            LocalFile::Special(_, kind) => *kind,
+            LocalFile::Directory(..) => FileType::Directory,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            LocalFile::RegularFile(path)
+            | LocalFile::Symlink(path)
+            | LocalFile::Special(path, _)
+            | LocalFile::Directory(path, _) => path,
+        }
+    }
+
+    pub fn path_mut(&mut self) -> &mut PathBuf {
+        match self {
+            LocalFile::RegularFile(path)
+            | LocalFile::Symlink(path)
+            | LocalFile::Special(path, _)
+            | LocalFile::Directory(path, _) => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_from_metadata() {
+        let path = std::env::temp_dir().join(format!("mizumochi_fifo_{}", std::process::id()));
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o644) }, 0);
+
+        let file = LocalFile::from_metadata(path.clone(), &fs::symlink_metadata(&path).unwrap());
+        assert_eq!(file.kind(), FileType::NamedPipe);
+        assert_eq!(file.path(), &path);
+
+        let dir = std::env::temp_dir();
+        let file = LocalFile::from_metadata(dir.clone(), &fs::symlink_metadata(&dir).unwrap());
+        assert_eq!(file.kind(), FileType::Directory);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
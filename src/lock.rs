@@ -0,0 +1,261 @@
+//! Byte-range locks of the files (see fcntl(2)).
+use crate::localfile::Inode;
+use std::collections::HashMap;
+use std::os::raw::c_int;
+
+/// `Lock` holds the range `start..=end` of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    /// `F_RDLCK`, `F_WRLCK` or `F_UNLCK`.
+    pub typ: c_int,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// `LockTable` tracks the locks per inode and lock owner.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: HashMap<Inode, Vec<Lock>>,
+}
+
+impl LockTable {
+    pub fn new() -> LockTable {
+        LockTable::default()
+    }
+
+    /// Returns the lock held by another owner which conflicts with the given one.
+    pub fn find_conflict(&self, ino: Inode, lock: &Lock) -> Option<&Lock> {
+        if lock.typ == libc::F_UNLCK {
+            return None;
+        }
+
+        self.locks
+            .get(&ino)
+            .and_then(|locks| locks.iter().find(|l| l.conflicts(lock)))
+    }
+
+    /// Acquires, modifies or releases (`F_UNLCK`) the lock.
+    /// Returns `EAGAIN` if another owner holds a conflicting lock.
+    pub fn set(&mut self, ino: Inode, lock: Lock) -> Result<(), c_int> {
+        if self.find_conflict(ino, &lock).is_some() {
+            return Err(libc::EAGAIN);
+        }
+
+        let locks = self.locks.entry(ino).or_default();
+
+        // Remove the range from the locks of the owner, splitting them if necessary.
+        let mut remains = Vec::with_capacity(locks.len() + 1);
+        for l in locks.drain(..) {
+            if l.owner != lock.owner || !l.overlaps(&lock) {
+                remains.push(l);
+                continue;
+            }
+            if l.start < lock.start {
+                remains.push(Lock {
+                    end: lock.start - 1,
+                    ..l.clone()
+                });
+            }
+            if lock.end < l.end {
+                remains.push(Lock {
+                    start: lock.end + 1,
+                    ..l
+                });
+            }
+        }
+        if lock.typ != libc::F_UNLCK {
+            remains.push(lock);
+        }
+        *locks = remains;
+
+        if locks.is_empty() {
+            self.locks.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Releases the all locks of the owner on the file (e.g., when the file is closed).
+    pub fn release_owner(&mut self, ino: Inode, owner: u64) {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|l| l.owner != owner);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+    }
+}
+
+/// `Waiters` keeps the requests waiting for the conflicting locks to be released (`F_SETLKW`).
+#[derive(Debug)]
+pub struct Waiters<T> {
+    waiters: Vec<(Inode, Lock, T)>,
+}
+
+impl<T> Waiters<T> {
+    pub fn new() -> Waiters<T> {
+        Waiters {
+            waiters: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, ino: Inode, lock: Lock, waiter: T) {
+        self.waiters.push((ino, lock, waiter));
+    }
+
+    /// Returns `true` if the owner of the conflicting lock waits for the given owner
+    /// directly or via the other owners, so that waiting for the lock never ends.
+    pub fn would_deadlock(&self, table: &LockTable, ino: Inode, lock: &Lock) -> bool {
+        let mut blocker = match table.find_conflict(ino, lock) {
+            Some(conflict) => conflict.owner,
+            None => return false,
+        };
+
+        // Each owner waits for one lock at most.
+        for _ in 0..self.waiters.len() {
+            let next = self
+                .waiters
+                .iter()
+                .find(|(_, l, _)| l.owner == blocker)
+                .and_then(|(ino, l, _)| table.find_conflict(*ino, l));
+            match next {
+                Some(conflict) if conflict.owner == lock.owner => return true,
+                Some(conflict) => blocker = conflict.owner,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// Acquires the locks no longer blocked in the order of the requests,
+    /// and returns their waiters.
+    pub fn acquire(&mut self, table: &mut LockTable) -> Vec<T> {
+        let mut acquired = Vec::new();
+        let mut remains = Vec::with_capacity(self.waiters.len());
+        for (ino, lock, waiter) in self.waiters.drain(..) {
+            if table.find_conflict(ino, &lock).is_none() {
+                // Never fails without the conflicts.
+                let _ = table.set(ino, lock);
+                acquired.push(waiter);
+            } else {
+                remains.push((ino, lock, waiter));
+            }
+        }
+        self.waiters = remains;
+        acquired
+    }
+
+    /// Removes the all waiters (e.g., on unmount).
+    pub fn drain(&mut self) -> Vec<T> {
+        self.waiters
+            .drain(..)
+            .map(|(_, _, waiter)| waiter)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: c_int) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    #[test]
+    fn test_lock_table() {
+        let mut table = LockTable::new();
+
+        // Read locks are shared.
+        assert_eq!(table.set(2, lock(1, 0, 99, libc::F_RDLCK)), Ok(()));
+        assert_eq!(table.set(2, lock(2, 50, 149, libc::F_RDLCK)), Ok(()));
+        assert_eq!(
+            table.set(2, lock(3, 0, u64::MAX, libc::F_WRLCK)),
+            Err(libc::EAGAIN)
+        );
+        // The other files are not affected.
+        assert_eq!(table.set(3, lock(3, 0, u64::MAX, libc::F_WRLCK)), Ok(()));
+
+        // Unlocking the middle of the range splits the lock.
+        assert_eq!(table.set(2, lock(1, 10, 19, libc::F_UNLCK)), Ok(()));
+        assert_eq!(
+            table.find_conflict(2, &lock(3, 10, 19, libc::F_WRLCK)),
+            None
+        );
+        assert_eq!(
+            table.find_conflict(2, &lock(3, 0, 9, libc::F_WRLCK)),
+            Some(&lock(1, 0, 9, libc::F_RDLCK))
+        );
+        assert_eq!(
+            table.find_conflict(2, &lock(3, 20, 29, libc::F_WRLCK)),
+            Some(&lock(1, 20, 99, libc::F_RDLCK))
+        );
+
+        // The owner can upgrade its own lock unless the others hold it.
+        assert_eq!(table.set(2, lock(1, 0, 9, libc::F_WRLCK)), Ok(()));
+        assert_eq!(
+            table.set(2, lock(1, 50, 59, libc::F_WRLCK)),
+            Err(libc::EAGAIN)
+        );
+
+        table.release_owner(2, 1);
+        table.release_owner(2, 2);
+        assert_eq!(table.set(2, lock(3, 0, u64::MAX, libc::F_WRLCK)), Ok(()));
+    }
+    #[test]
+    fn test_waiters() {
+        let mut table = LockTable::new();
+        let mut waiters = Waiters::new();
+        assert_eq!(table.set(2, lock(1, 0, 9, libc::F_WRLCK)), Ok(()));
+        assert_eq!(table.set(2, lock(2, 10, 19, libc::F_WRLCK)), Ok(()));
+
+        // The owner 3 waits for the owner 1.
+        let waiting = lock(3, 0, 9, libc::F_RDLCK);
+        assert!(!waiters.would_deadlock(&table, 2, &waiting));
+        waiters.push(2, waiting, "3");
+        assert!(waiters.acquire(&mut table).is_empty());
+
+        // The owner 1 waits for the owner 2, which cannot wait for the owner 3 or 1.
+        let waiting = lock(1, 10, 19, libc::F_WRLCK);
+        assert!(!waiters.would_deadlock(&table, 2, &waiting));
+        waiters.push(2, waiting, "1");
+        assert!(waiters.would_deadlock(&table, 2, &lock(2, 0, 9, libc::F_WRLCK)));
+        assert!(!waiters.would_deadlock(&table, 2, &lock(2, 20, 29, libc::F_WRLCK)));
+
+        // Releasing the locks of the owner 2 wakes the owner 1,
+        // but the owner 3 waits until the owner 1 releases its lock.
+        table.release_owner(2, 2);
+        assert_eq!(vec!["1"], waiters.acquire(&mut table));
+        assert_eq!(
+            table.find_conflict(2, &lock(4, 10, 19, libc::F_RDLCK)),
+            Some(&lock(1, 10, 19, libc::F_WRLCK))
+        );
+        assert!(waiters.acquire(&mut table).is_empty());
+
+        table.release_owner(2, 1);
+        assert_eq!(vec!["3"], waiters.acquire(&mut table));
+        assert!(waiters.drain().is_empty());
+
+        waiters.push(2, lock(4, 0, 9, libc::F_WRLCK), "4");
+        assert_eq!(vec!["4"], waiters.drain());
+    }
+}
@@ -12,6 +12,7 @@ extern crate prometrics;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 #[macro_use]
 extern crate slog;
@@ -19,21 +20,33 @@ extern crate slog_async;
 extern crate slog_term;
 
 mod config;
+mod handle;
 mod http;
 mod localfile;
+mod lock;
 mod metrics;
 mod mizumochi;
+mod mounts;
+mod overlay;
+mod permission;
+mod shutdown;
 mod state;
+mod usage;
+mod xattr;
 
 use crate::config::*;
-use crate::mizumochi::Mizumochi;
-use atomic_immut::AtomicImmut;
+use crate::mounts::{Mounts, DEFAULT_MOUNT};
+use crate::shutdown::Shutdown;
 use clap::{Arg, SubCommand};
 use slog::{Drain, Level};
-use std::sync::Arc;
+use std::convert::TryFrom;
+use std::process::ExitCode;
 use std::time::Duration;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // The signals are handled by the thread spawned later.
+    shutdown::block_signals()?;
+
     let matches = app_from_crate!()
         .arg(
             Arg::with_name("SPEED")
@@ -44,6 +57,101 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long_help("you can put suffixes (KBps, MBps, GBps) at the tail (examples: 1024Bps, 4096KBps, 5Mbps)\nthe default is Bps")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("CAPACITY")
+                .short("c")
+                .long("capacity")
+                .value_name("Byte")
+                .help("Sets virtual capacity of the mountpoint")
+                .long_help("you can put suffixes (KB, MB, GB, TB) at the tail (examples: 1024B, 512MB, 10GB)\nwrites exceeding the capacity fail")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CAPACITY_ERROR")
+                .long("capacity-error")
+                .help("Sets error returned when the capacity is exceeded")
+                .takes_value(true)
+                .possible_values(&["ENOSPC", "EDQUOT"])
+                .default_value("ENOSPC"),
+        )
+        .arg(
+            Arg::with_name("MAX_FILES")
+                .long("max-files")
+                .help("Sets the maximum number of files on the mountpoint")
+                .long_help("creating files fails with ENOSPC after reaching this limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("DURABILITY")
+                .long("durability")
+                .help("Sets when the written data are synced to the original files")
+                .long_help("pass_through (default): synced on fsync and close\nalways_sync: synced on every write\nnever_sync: never synced")
+                .takes_value(true)
+                .possible_values(&["pass_through", "always_sync", "never_sync"]),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .long("config")
+                .value_name("FILE")
+                .help("Loads the config and the mount options from the JSON file")
+                .long_help("the options on the command line override the file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ALLOW_OTHER")
+                .long("allow-other")
+                .help("Allows the other users to access the mountpoint")
+                .long_help("requires `user_allow_other` in /etc/fuse.conf unless mizumochi runs as root"),
+        )
+        .arg(
+            Arg::with_name("DEFAULT_PERMISSIONS")
+                .long("default-permissions")
+                .help("Lets the kernel check the permissions of all the operations"),
+        )
+        .arg(
+            Arg::with_name("READ_ONLY")
+                .long("ro")
+                .help("Mounts the mountpoint read-only"),
+        )
+        .arg(
+            Arg::with_name("FSNAME")
+                .long("fsname")
+                .help("Sets the file system name shown in the mount table")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("SUBTYPE")
+                .long("subtype")
+                .help("Sets the file system subtype shown in the mount table")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("AUTO_UNMOUNT")
+                .long("auto-unmount")
+                .help("Unmounts the mountpoint when mizumochi exits"),
+        )
+        .arg(
+            Arg::with_name("MAX_READ")
+                .long("max-read")
+                .value_name("Byte")
+                .help("Sets the maximum size of the read requests")
+                .long_help("you can put suffixes (KB, MB) at the tail (examples: 4096B, 128KB)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("UPPER_DIR")
+                .long("upper-dir")
+                .value_name("DIR")
+                .help("Writes the changes to this directory instead of the original directory")
+                .long_help("the original directory is not modified (overlay mode)\nthe changes are discarded by `POST /mounts/{name}/reset`")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("FORCE_UNMOUNT_STALE")
+                .long("force-unmount-stale")
+                .help("Unmounts the mountpoint left by the previous mizumochi which has exited")
+                .long_help("otherwise mizumochi fails when the mountpoint is \"Transport endpoint is not connected\""),
+        )
         .arg(
             Arg::with_name("HTTP_PORT")
                 .short("p")
@@ -55,13 +163,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .arg(
             Arg::with_name("ORIGINAL_DIR")
                 .help("Sets a directory has original files")
-                .required(true)
+                .required_unless("CONFIG")
+                .requires("MOUNTPOINT")
                 .index(1),
         )
         .arg(
             Arg::with_name("MOUNTPOINT")
                 .help("Mountpoint directory")
-                .required(true)
+                .required_unless("CONFIG")
                 .index(2),
         )
          .subcommand(
@@ -87,17 +196,68 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                  ))
         .get_matches();
 
-    let original_dir = matches.value_of("ORIGINAL_DIR").unwrap();
-    let mountpoint = matches.value_of("MOUNTPOINT").unwrap();
     let http_port: u16 = matches.value_of("HTTP_PORT").unwrap().parse()?;
 
-    let mut config: Config = Default::default();
+    let file = match matches.value_of("CONFIG") {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    let mut config = file.config.unwrap_or_default();
+    let mut mount_options = file.mount;
 
     // Override the config if there are given options.
     if let Some(speed) = matches.value_of("SPEED") {
         config.speed = speed.parse()?;
     }
 
+    if let Some(capacity) = matches.value_of("CAPACITY") {
+        let error = match matches.value_of("CAPACITY_ERROR") {
+            Some("EDQUOT") => NoSpaceError::QuotaExceeded,
+            _ => NoSpaceError::NoSpace,
+        };
+
+        config.capacity = Some(Capacity {
+            bytes: parse_size(capacity)?,
+            error,
+        });
+    }
+
+    if let Some(max_files) = matches.value_of("MAX_FILES") {
+        config.max_files = Some(max_files.parse()?);
+    }
+
+    if let Some(durability) = matches.value_of("DURABILITY") {
+        config.durability = durability.parse()?;
+    }
+
+    if matches.is_present("ALLOW_OTHER") {
+        mount_options.allow_other = true;
+    }
+
+    if matches.is_present("DEFAULT_PERMISSIONS") {
+        mount_options.default_permissions = true;
+    }
+
+    if matches.is_present("READ_ONLY") {
+        mount_options.read_only = true;
+    }
+
+    if let Some(fsname) = matches.value_of("FSNAME") {
+        mount_options.fsname = Some(fsname.to_string());
+    }
+
+    if let Some(subtype) = matches.value_of("SUBTYPE") {
+        mount_options.subtype = Some(subtype.to_string());
+    }
+
+    if matches.is_present("AUTO_UNMOUNT") {
+        mount_options.auto_unmount = true;
+    }
+
+    if let Some(max_read) = matches.value_of("MAX_READ") {
+        mount_options.max_read = Some(u32::try_from(parse_size(max_read)?)?);
+    }
+
     if let Some(matches) = matches.subcommand_matches("periodic") {
         let mut p = config::Condition::default_periodic();
 
@@ -132,25 +292,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let drain = slog::Fuse::new(slog::LevelFilter::new(drain, Level::Info));
     let logger = slog::Logger::root(drain, o!());
 
-    info!(logger, "original directory: {}", original_dir);
-    info!(logger, "mountpoint: {}", mountpoint);
-    info!(logger, "config: {}", config);
+    // The mount given on the command line is named `DEFAULT_MOUNT`.
+    let default_mount = matches.value_of("ORIGINAL_DIR").map(|original_dir| {
+        let spec = MountSpec {
+            original_dir: original_dir.into(),
+            mountpoint: matches.value_of("MOUNTPOINT").unwrap().into(),
+            upper_dir: matches.value_of("UPPER_DIR").map(Into::into),
+            options: mount_options,
+            config: Some(config),
+        };
+        (DEFAULT_MOUNT.to_string(), spec)
+    });
+    let shutdown = Shutdown::new();
+    let mounts = Mounts::new(logger.clone(), matches.is_present("FORCE_UNMOUNT_STALE"));
 
-    let config = Arc::new(AtomicImmut::new(config));
-    http::start_server(logger.clone(), http_port, Arc::clone(&config))?;
+    let started = default_mount
+        .into_iter()
+        .chain(file.mounts)
+        .try_for_each(|(name, spec)| mounts.add(&name, spec))
+        .map_err(Box::from)
+        .and_then(|()| {
+            http::start_server(logger.clone(), http_port, mounts.clone(), shutdown.clone())
+        });
+    if let Err(error) = started {
+        error!(logger, "{}", error);
+        mounts.remove_all();
+        mounts.wait();
+        return Err(error);
+    }
 
-    let m = Mizumochi::new(
-        logger.clone(),
-        original_dir.into(),
-        mountpoint.into(),
-        config,
-    );
+    // mizumochi keeps serving the HTTP API even if no mounts remain until the signal.
+    shutdown::spawn_signal_handler(logger.clone(), shutdown.clone(), mounts.clone());
+    shutdown.wait();
+    mounts.wait();
 
-    if let Err(error) = m.mount() {
-        error!(logger, "{}", error);
-        Err(Box::new(error))
+    if shutdown.is_failed() {
+        Err("mizumochi is shut down by an error".into())
+    } else if let Some(signal) = shutdown.signal() {
+        // Exit as if the signal terminated mizumochi, like the shells report it.
+        info!(logger, "shut down by signal {}", signal);
+        Ok(ExitCode::from(128 + signal as u8))
     } else {
-        Ok(())
+        info!(logger, "shut down");
+        Ok(ExitCode::SUCCESS)
     }
 }
 
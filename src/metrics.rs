@@ -39,9 +39,10 @@ pub struct Metrics {
     pub speed_limit_disabled: Counter,
 }
 impl Metrics {
-    pub fn new() -> Self {
+    /// The metrics are labeled with the name of the mount.
+    pub fn new(mount: &str) -> Self {
         let mut builder = MetricBuilder::new();
-        builder.namespace("mizumochi");
+        builder.namespace("mizumochi").label("mount", mount);
         let build_io_operations_metric = |name| {
             builder
                 .counter("io_operations_total")
@@ -1,28 +1,66 @@
 // FIXME: Refactor error
-use crate::config::{Config, Operation, Speed};
+use crate::config::{
+    Config, Durability, Faults, FsyncFault, LockFault, Operation, Speed, TimestampFault,
+    TruncateFault, XattrFault,
+};
+use crate::handle::Handle;
 use crate::localfile::{Inode, LocalFile};
+use crate::lock::{Lock, LockTable, Waiters};
 use crate::metrics::Metrics;
+use crate::overlay::{self, Overlay};
+use crate::permission::{self, Caller};
+use crate::shutdown::Shutdown;
 use crate::state::{State, StateManager};
+use crate::usage::{compute_disk_usage, DiskUsage};
+use crate::xattr;
 use atomic_immut::AtomicImmut;
 use fuse::{self, *};
 use slog::Logger;
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io;
 use std::mem;
 use std::os::raw::c_int;
 use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use time::{PreciseTime, Timespec};
 
 type FileHandler = u64;
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+/// Replies the error number and returns from the callback if the result is an error.
+macro_rules! try_reply {
+    ($reply:expr, $result:expr) => {
+        if let Err(ecode) = $result {
+            $reply.error(ecode);
+            return;
+        }
+    };
+}
+
 const ROOT_DIR_INO: u64 = 1;
+// The inode numbers from this are allocated for the files whose original inode numbers cannot be used.
+const FALLBACK_INO_BASE: u64 = 1 << 62;
+// The fetched directories are compared with the original ones at most once per the interval.
+const REVALIDATION_INTERVAL: Duration = Duration::from_secs(1);
+// The disk usage is computed again at most once per the interval.
+const DISK_USAGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The fields replied to `statfs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Statfs {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+}
 
 pub struct Mizumochi {
     logger: Logger,
@@ -30,25 +68,66 @@ pub struct Mizumochi {
     state_manager: StateManager,
     config: Arc<AtomicImmut<Config>>,
 
-    // FIXME: use simple allocator.
     ino_count: Inode,
     fh_count: FileHandler,
 
-    fh_map: HashMap<FileHandler, File>,
+    fh_map: HashMap<FileHandler, Handle>,
+    // The entries of the directories listed via the handles.
+    dir_entries: HashMap<FileHandler, Vec<(Inode, FileType, PathBuf)>>,
     file_map: HashMap<Inode, LocalFile>,
+    // The files whose all links have been removed while they are open.
+    // They are forgotten when they are closed.
+    unlinked: HashSet<Inode>,
+    // The inodes of the files keyed by the device and the inode in the original directory.
+    // The hard links share the inode.
+    ino_map: HashMap<(u64, u64), Inode>,
+    // The keys of `ino_map` per inode to forget them.
+    ino_keys: HashMap<Inode, Vec<(u64, u64)>>,
+    // The device of the original directory.
+    root_dev: u64,
+    // How many times the inodes have been looked up by the kernel (see forget).
+    lookup_counts: HashMap<Inode, u64>,
+    // When the fetched directories were compared with the original ones, and their mtime then.
+    dir_checked: HashMap<Inode, (Instant, SystemTime)>,
+
+    // The generation is incremented every time the device disappears.
+    device_generation: u64,
+    device_present: bool,
+
+    // The directories which `fsyncdir` has already failed by `FsyncFault::FailOnce`.
+    fsyncdir_failed: HashSet<Inode>,
+
+    locks: LockTable,
+    // The replies to `F_SETLKW` deferred until the conflicting locks are released.
+    lock_waiters: Waiters<ReplyEmpty>,
+
+    // The timestamps reported while `TimestampFault::Stale` is injected.
+    // They are taken when the state gets unstable (see snapshot_times).
+    stale_times: HashMap<Inode, (Timespec, Timespec, Timespec)>,
+
+    // The bytes used in `original_dir`.
+    disk_usage: DiskUsage,
 
     original_dir: PathBuf,
     mountpoint: PathBuf,
+    // The original directory is not modified in the overlay mode.
+    overlay: Option<Overlay>,
 
     metrics: Metrics,
+
+    // The sleeping requests are released when the shutdown is requested.
+    shutdown: Shutdown,
 }
 
 impl Mizumochi {
     pub fn new(
         logger: Logger,
+        name: &str,
         original_dir: PathBuf,
         mountpoint: PathBuf,
+        upper_dir: Option<PathBuf>,
         config: Arc<AtomicImmut<Config>>,
+        shutdown: Shutdown,
     ) -> Mizumochi {
         let cond = config.load().condition.clone();
         let state_manager = StateManager::new(cond);
@@ -60,21 +139,44 @@ impl Mizumochi {
             config,
 
             fh_count: 1,
-            // inode number begins from the next of `ROOT_DIR_INO`.
-            ino_count: ROOT_DIR_INO + 1,
+            ino_count: FALLBACK_INO_BASE,
             fh_map: HashMap::new(),
+            dir_entries: HashMap::new(),
             file_map: HashMap::new(),
+            unlinked: HashSet::new(),
+            ino_map: HashMap::new(),
+            ino_keys: HashMap::new(),
+            root_dev: 0,
+            lookup_counts: HashMap::new(),
+            dir_checked: HashMap::new(),
+
+            device_generation: 0,
+            device_present: true,
+
+            fsyncdir_failed: HashSet::new(),
+
+            locks: LockTable::new(),
+            lock_waiters: Waiters::new(),
+
+            stale_times: HashMap::new(),
+
+            disk_usage: DiskUsage::new(DISK_USAGE_INTERVAL),
 
             mountpoint,
+            overlay: upper_dir.map(|upper_dir| Overlay::new(original_dir.clone(), upper_dir)),
             original_dir,
 
-            metrics: Metrics::new(),
+            metrics: Metrics::new(name),
+
+            shutdown,
         }
     }
 
-    pub fn mount(self) -> Result<(), io::Error> {
+    /// Mounts the file system. The returned session serves the requests until it is unmounted.
+    pub fn mount(self, options: &[OsString]) -> Result<Session<Mizumochi>, io::Error> {
         let mountpoint = self.mountpoint.clone();
-        fuse::mount(self, &mountpoint, &[])
+        let options = options.iter().map(OsString::as_os_str).collect::<Vec<_>>();
+        Session::new(self, &mountpoint, &options)
     }
 
     fn init(&mut self) -> Result<(), io::Error> {
@@ -86,72 +188,270 @@ impl Mizumochi {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not directory"));
         }
 
+        // Resolve the symbolic links to the original directory itself
+        // because the files are not followed after this.
+        self.original_dir = fs::canonicalize(&self.original_dir)?;
+
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata = fs::metadata(&self.original_dir)?;
+            self.root_dev = metadata.dev();
+            self.map_key((metadata.dev(), metadata.ino()), ROOT_DIR_INO);
+        }
+
+        if let Some(ref overlay) = self.overlay {
+            use std::os::unix::fs::MetadataExt;
+
+            let overlay = Overlay::open(&self.original_dir, overlay.upper(), &self.mountpoint)?;
+            let metadata = fs::metadata(overlay.upper())?;
+            self.map_key((metadata.dev(), metadata.ino()), ROOT_DIR_INO);
+            self.overlay = Some(overlay);
+        }
+
         // Initialize the state.
         self.state_manager.init();
         info!(self.logger, "State: {:?}", self.state_manager.state());
 
         let path = self.original_dir.clone();
-        self.fetch_files_if_not_found(ROOT_DIR_INO, &path)?;
+        self.revalidate_dir(ROOT_DIR_INO, &path)?;
 
         Ok(())
     }
 
-    fn fetch_files_if_not_found(
-        &mut self,
-        root_ino: Inode,
-        root_dir: &PathBuf,
-    ) -> Result<(), io::Error> {
-        if !root_dir.is_dir() {
+    /// Fetches the files in the directory, or fetches them again
+    /// if the original directory has been changed by the others.
+    fn revalidate_dir(&mut self, root_ino: Inode, root_dir: &PathBuf) -> Result<(), io::Error> {
+        let now = Instant::now();
+        let known = match self.file_map.get(&root_ino) {
+            Some(LocalFile::Directory(_, Some(files))) => match self.dir_checked.get(&root_ino) {
+                Some((checked_at, _))
+                    if now.duration_since(*checked_at) < REVALIDATION_INTERVAL =>
+                {
+                    // Already fetched.
+                    return Ok(());
+                }
+                _ => Some(files.clone()),
+            },
+            _ => None,
+        };
+
+        let metadata = fs::metadata(root_dir)?;
+        if !metadata.is_dir() {
             error!(
                 self.logger,
-                "fetch_files_if_not_found error: path: {:?} is directory, ino: {}",
-                root_dir,
-                root_ino
+                "revalidate_dir error: path: {:?} is directory, ino: {}", root_dir, root_ino
             );
             return Err(io::Error::new(io::ErrorKind::Other, "Not directory"));
         }
+        let mtime = self.dir_modified(root_dir, &metadata)?;
 
-        if let Some(LocalFile::Directory(_, Some(_))) = self.file_map.get(&root_ino) {
-            // Already fetched.
+        let changed = match self.dir_checked.get(&root_ino) {
+            Some((_, checked_mtime)) => *checked_mtime != mtime,
+            None => true,
+        };
+        if known.is_some() && !changed {
+            self.dir_checked.insert(root_ino, (now, mtime));
             return Ok(());
         }
+        let known = known.unwrap_or_default();
 
         info!(
             self.logger,
-            "fetch_files_if_not_found: ino: {}, path: {:?}", root_ino, root_dir
+            "revalidate_dir: ino: {}, path: {:?}", root_ino, root_dir
         );
 
         let mut files = Vec::new();
+        let mut listed = HashMap::new();
 
         // Fetch the all files in the directory.
-        for entry in fs::read_dir(root_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-
+        for path in self.list_dir(root_dir)? {
             let filename = path
                 .file_name()
                 .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot get filename"))?;
 
-            let file = if path.is_dir() {
-                // The files in the directory is loaded later (see lookup).
-                LocalFile::Directory(path.clone(), None)
-            } else {
-                LocalFile::RegularFile(path.clone())
-            };
+            // Note that the symbolic links are not followed.
+            let metadata = fs::symlink_metadata(&path)?;
+            // The files in the directory is loaded later (see lookup).
+            let file = LocalFile::from_metadata(path.clone(), &metadata);
 
-            let ino = self.ino_count;
-            self.ino_count += 1;
-            self.file_map.insert(ino, file);
+            // The files fetched before (including the hard links) keep their inodes
+            // unless the inode number is reused for another kind of file.
+            let ino = self.assign_ino(&metadata);
+            match self.file_map.get(&ino) {
+                Some(fetched) if fetched.kind() == file.kind() => {}
+                _ => {
+                    self.file_map.insert(ino, file);
+                }
+            }
 
             files.push((ino, filename.into()));
+            listed.insert(PathBuf::from(filename), ino);
         }
 
+        let removed = known
+            .into_iter()
+            .filter(|(ino, name)| listed.get(name) != Some(ino))
+            .collect::<Vec<_>>();
+
         self.file_map
             .insert(root_ino, LocalFile::Directory(root_dir.into(), Some(files)));
+        self.dir_checked.insert(root_ino, (now, mtime));
+
+        // Forget the files removed by the others.
+        for (ino, name) in removed {
+            self.remove_link(root_ino, ino, name.as_os_str(), &root_dir.join(&name));
+        }
 
         Ok(())
     }
 
+    /// Returns the modification time of the directory (of the merged view in the overlay mode).
+    fn dir_modified(&self, dir: &Path, metadata: &fs::Metadata) -> Result<SystemTime, io::Error> {
+        match self.overlay {
+            Some(ref overlay) => overlay.modified(dir),
+            None => metadata.modified(),
+        }
+    }
+
+    /// Records the modification time of the directory changed by mizumochi itself,
+    /// so that the change does not make `revalidate_dir` list the directory again.
+    fn record_dir_modified(&mut self, dir: Inode) {
+        let path = match self.file_map.get(&dir) {
+            Some(LocalFile::Directory(path, Some(_))) => path,
+            _ => return,
+        };
+        let mtime = fs::metadata(path).and_then(|metadata| self.dir_modified(path, &metadata));
+        if let (Some((_, checked_mtime)), Ok(mtime)) = (self.dir_checked.get_mut(&dir), mtime) {
+            *checked_mtime = mtime;
+        }
+    }
+
+    /// Returns the paths of the files in the directory.
+    fn list_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        list_dir(self.overlay.as_ref(), dir)
+    }
+
+    /// Returns the inode of the file in the original directory.
+    ///
+    /// The inode number of the original file is used to keep it stable across restarts
+    /// unless it conflicts (e.g., the files on the other devices).
+    fn assign_ino(&mut self, metadata: &fs::Metadata) -> Inode {
+        use std::os::unix::fs::MetadataExt;
+
+        self.assign_ino_for_key((metadata.dev(), metadata.ino()))
+    }
+
+    fn assign_ino_for_key(&mut self, key: (u64, u64)) -> Inode {
+        if let Some(ino) = self.ino_map.get(&key) {
+            return *ino;
+        }
+
+        let (dev, original_ino) = key;
+        let usable = dev == self.root_dev
+            && original_ino != ROOT_DIR_INO
+            && original_ino < FALLBACK_INO_BASE
+            && !self.file_map.contains_key(&original_ino);
+        let ino = if usable {
+            original_ino
+        } else {
+            while self.file_map.contains_key(&self.ino_count) {
+                self.ino_count += 1;
+            }
+            self.ino_count += 1;
+            self.ino_count - 1
+        };
+
+        self.map_key(key, ino);
+        ino
+    }
+
+    fn map_key(&mut self, key: (u64, u64), ino: Inode) {
+        self.ino_map.insert(key, ino);
+        self.ino_keys.entry(ino).or_default().push(key);
+    }
+
+    /// Forgets the keys of the inode in `ino_map`.
+    fn unmap_keys(&mut self, ino: Inode) {
+        for key in self.ino_keys.remove(&ino).unwrap_or_default() {
+            self.ino_map.remove(&key);
+        }
+    }
+
+    /// Counts the inode replied to the kernel via `lookup`, `create` and so on.
+    fn count_lookup(&mut self, ino: Inode) {
+        *self.lookup_counts.entry(ino).or_insert(0) += 1;
+    }
+
+    /// Forgets the file after the kernel drops all the references to it
+    /// unless it is still listed in the directory or open.
+    fn forget(&mut self, ino: Inode, nlookup: u64) {
+        if ino == ROOT_DIR_INO {
+            return;
+        }
+
+        match self.lookup_counts.get_mut(&ino) {
+            Some(count) if nlookup < *count => {
+                *count -= nlookup;
+                return;
+            }
+            _ => {
+                self.lookup_counts.remove(&ino);
+            }
+        }
+
+        self.evict(ino);
+    }
+
+    /// Removes the file which the kernel no longer refers to.
+    /// The directory drops its listing, and the files in it are removed unless they are referred to.
+    fn evict(&mut self, ino: Inode) {
+        if ino == ROOT_DIR_INO || self.lookup_counts.contains_key(&ino) || self.is_open(ino) {
+            return;
+        }
+
+        // The files in the directory are fetched again when it is looked up.
+        let files = match self.file_map.get_mut(&ino) {
+            Some(LocalFile::Directory(_, files)) => files.take(),
+            _ => None,
+        };
+        if let Some(files) = files {
+            self.dir_checked.remove(&ino);
+            for (fino, _) in files {
+                self.evict(fino);
+            }
+        }
+
+        // The files listed in the fetched directories are kept for readdir.
+        if self.is_listed(ino) {
+            return;
+        }
+        if self.file_map.remove(&ino).is_some() {
+            self.unmap_keys(ino);
+            self.dir_checked.remove(&ino);
+            self.fsyncdir_failed.remove(&ino);
+        }
+    }
+
+    /// Returns whether the directory of the file lists it.
+    fn is_listed(&self, ino: Inode) -> bool {
+        let path = match self.file_map.get(&ino) {
+            Some(file) => file.path(),
+            None => return false,
+        };
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return false,
+        };
+
+        match self.file_map.get(&self.parent_ino(ino, path)) {
+            Some(LocalFile::Directory(_, Some(files))) => files
+                .iter()
+                .any(|(fino, filename)| *fino == ino && filename.as_os_str() == name),
+            _ => false,
+        }
+    }
+
     fn change_state_if_necessary(&mut self, op: Operation) -> &State {
         let prev_state = self.state_manager.state().clone();
 
@@ -170,7 +470,8 @@ impl Mizumochi {
             match (prev_state, state) {
                 (State::Stable, State::Unstable) => {
                     self.metrics.speed_limit_enabled.increment();
-                    info!(self.logger, "--- Enable unstable mode ---")
+                    info!(self.logger, "--- Enable unstable mode ---");
+                    self.snapshot_times();
                 }
                 (State::Unstable, State::Stable) => {
                     self.metrics.speed_limit_enabled.increment();
@@ -183,823 +484,3430 @@ impl Mizumochi {
         self.state_manager.state()
     }
 
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, io::Error> {
-        let (inode, path) = match self
-            .file_map
-            .get(&parent)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, ""))?
-        {
-            LocalFile::Directory(_, None) => {
-                Err(io::Error::new(io::ErrorKind::Other, "not fetched"))
-            }
-            LocalFile::Directory(path, Some(files)) => {
-                // Find the file by the given name.
-                let f = files
-                    .iter()
-                    .find(|(_, path)| Some(name) == path.file_name());
+    /// Returns the free bytes in the virtual capacity, or `None` if the capacity is unlimited.
+    fn free_bytes(&mut self) -> Result<Option<u64>, io::Error> {
+        let capacity = match self.config.load().capacity {
+            Some(ref capacity) => capacity.bytes,
+            None => return Ok(None),
+        };
+
+        // The files shown on the mountpoint are counted in the overlay mode.
+        let overlay = self.overlay.clone();
+        let original_dir = self.original_dir.clone();
+        let used = match self.disk_usage.get(move || {
+            compute_disk_usage(&original_dir, &|dir| list_dir(overlay.as_ref(), dir))
+        })? {
+            Some(used) => used,
+            // The capacity is not enforced until the usage is computed.
+            None => return Ok(None),
+        };
+
+        Ok(Some(capacity.saturating_sub(used)))
+    }
+
+    /// Checks the virtual capacity has `growth` bytes at least.
+    fn ensure_capacity(&mut self, growth: u64) -> Result<(), c_int> {
+        let free = self.free_bytes().map_err(|error| {
+            error!(self.logger, "disk usage error: {}", error);
+            libc::EIO
+        })?;
+
+        match (free, &self.config.load().capacity) {
+            (Some(free), Some(capacity)) if free < growth => Err(capacity.error.errno()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks one more file can be created under the maximum number of files.
+    fn ensure_file_slot(&self) -> Result<(), c_int> {
+        match self.config.load().max_files {
+            Some(max_files) if max_files <= self.file_map.len() as u64 => Err(libc::ENOSPC),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the bytes newly used by the written data.
+    fn consume_bytes(&mut self, growth: u64) {
+        self.disk_usage.consume(growth);
+    }
+
+    /// Records the bytes freed by removing files.
+    fn release_bytes(&mut self, size: u64) {
+        self.disk_usage.release(size);
+    }
 
-                let (inode, _) = f.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, ""))?;
+    /// Returns the faults injected in the current state.
+    fn current_faults(&mut self, op: Operation) -> Faults {
+        if State::Unstable == *self.change_state_if_necessary(op) {
+            self.config.load().faults.clone()
+        } else {
+            Faults::default()
+        }
+    }
+
+    /// Returns how long the kernel caches the attributes and the entries.
+    fn ttl(&mut self) -> Timespec {
+        let unstable = State::Unstable == *self.change_state_if_necessary(Operation::Read);
+        let ttl = self.config.load().cache.ttl(unstable);
+        Timespec::new(ttl.as_secs() as i64, ttl.subsec_nanos() as i32)
+    }
+
+    /// Returns the flags of the opened file to control the page cache in the current state.
+    fn open_flags(&mut self) -> u32 {
+        let unstable = State::Unstable == *self.change_state_if_necessary(Operation::Read);
+        self.config.load().cache.open_flags(unstable)
+    }
+
+    /// Returns the inode of the parent directory of the file.
+    fn parent_ino(&self, ino: Inode, path: &Path) -> Inode {
+        use std::os::unix::fs::MetadataExt;
 
-                let mut path = path.clone();
-                path.push(name);
+        if ino == ROOT_DIR_INO {
+            return ROOT_DIR_INO;
+        }
+
+        path.parent()
+            .and_then(|parent| fs::symlink_metadata(parent).ok())
+            .and_then(|metadata| self.ino_map.get(&(metadata.dev(), metadata.ino())).cloned())
+            .unwrap_or(ROOT_DIR_INO)
+    }
+
+    /// Keeps the timestamps of the known files when the state gets unstable
+    /// to report them while `TimestampFault::Stale` is injected.
+    fn snapshot_times(&mut self) {
+        self.stale_times.clear();
+        if self.config.load().faults.timestamps != Some(TimestampFault::Stale) {
+            return;
+        }
 
-                Ok((*inode, path))
+        let inos = self.file_map.keys().cloned().collect::<Vec<_>>();
+        for ino in inos {
+            if let Ok(attr) = self.fetch_attr(ino) {
+                self.stale_times
+                    .insert(ino, (attr.atime, attr.mtime, attr.ctime));
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "it is not directory",
-            )),
-        }?;
+        }
+    }
 
-        if path.is_dir() {
-            self.fetch_files_if_not_found(inode, &path)?;
+    /// Applies `TimestampFault` to the attributes replied to the kernel.
+    /// The files unknown when the state got unstable keep the timestamps of the first reply.
+    fn fault_timestamps(&mut self, mut attr: FileAttr) -> FileAttr {
+        match self.current_faults(Operation::Read).timestamps {
+            Some(TimestampFault::Stale) => {
+                let (atime, mtime, ctime) = *self
+                    .stale_times
+                    .entry(attr.ino)
+                    .or_insert((attr.atime, attr.mtime, attr.ctime));
+                attr.atime = atime;
+                attr.mtime = mtime;
+                attr.ctime = ctime;
+            }
+            Some(TimestampFault::Skew(secs)) => {
+                attr.atime.sec += secs;
+                attr.mtime.sec += secs;
+                attr.ctime.sec += secs;
+                attr.crtime.sec += secs;
+            }
+            None => self.stale_times.clear(),
         }
+        attr
+    }
 
-        fetch_fileattr(inode, &path)
+    /// Replies the entry of the file, which the kernel counts as looked up (see forget).
+    fn reply_entry(&mut self, reply: ReplyEntry, attr: FileAttr) {
+        self.count_lookup(attr.ino);
+        reply.entry(&self.ttl(), &self.fault_timestamps(attr), 0)
     }
 
-    fn read(&mut self, fh: u64, buffer: &mut [u8], offset: i64, size: u32) -> Result<usize, c_int> {
-        let logger = &self.logger;
-        let f = self.fh_map.get_mut(&fh).ok_or(libc::ENOENT)?;
+    /// Returns an error if the device has disappeared in the current state.
+    fn ensure_device_present(&mut self) -> Result<(), c_int> {
+        match self.current_faults(Operation::Read).disappear {
+            Some(error) => {
+                if self.device_present {
+                    // Invalidate the all opened files.
+                    self.device_present = false;
+                    self.device_generation += 1;
+                    info!(self.logger, "--- The device disappears ---");
+                }
+                Err(error.errno())
+            }
+            None => {
+                if !self.device_present {
+                    self.device_present = true;
+                    info!(self.logger, "--- The device reappears ---");
+                }
+                Ok(())
+            }
+        }
+    }
 
-        let file_size = f.metadata().map_err(|_| libc::EIO)?.len();
+    /// Returns `EROFS` if the mountpoint behaves as read-only in the current state.
+    /// The device must be present as well.
+    fn ensure_writable(&mut self) -> Result<(), c_int> {
+        self.ensure_device_present()?;
 
-        let offset = offset as u64;
-        if offset < file_size {
-            if let Err(error) = f.seek(SeekFrom::Start(offset)) {
-                error!(logger, "seek error {}", error);
-                return Err(libc::EIO);
+        if self.current_faults(Operation::Write).read_only {
+            Err(libc::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, io::Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let (path, inode) = self.find_child(parent, name)?;
+        let inode = inode.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, ""))?;
+
+        // The forgotten file is recorded again.
+        if let Entry::Vacant(entry) = self.file_map.entry(inode) {
+            let metadata = fs::symlink_metadata(&path)?;
+            entry.insert(LocalFile::from_metadata(path.clone(), &metadata));
+            let key = (metadata.dev(), metadata.ino());
+            if !self.ino_map.contains_key(&key) {
+                self.map_key(key, inode);
             }
+        }
 
-            // Truncate the size to avoid overreading.
-            let size = if file_size < (offset + u64::from(size)) {
-                (file_size - offset) as usize
-            } else {
-                size as usize
-            };
+        if let Some(LocalFile::Directory(..)) = self.file_map.get(&inode) {
+            self.revalidate_dir(inode, &path)?;
+        }
+
+        fetch_fileattr(inode, &path)
+    }
 
-            f.read(&mut buffer[0..size]).map_err(|error| {
-                error!(logger, "read error {}", error);
+    fn read(&mut self, fh: u64, buffer: &mut [u8], offset: i64, size: u32) -> Result<usize, c_int> {
+        let handle = self.fh_map.get(&fh).ok_or(libc::ENOENT)?;
+        handle.ensure_generation(self.device_generation)?;
+
+        // Fill the requested size unless the end of the file is reached.
+        handle
+            .read_at(&mut buffer[0..size as usize], offset as u64)
+            .map_err(|error| {
+                error!(self.logger, "read error {}", error);
                 libc::EIO
             })
-        } else {
-            Ok(0)
-        }
     }
 
     fn write(&mut self, fh: u64, buffer: &[u8], offset: i64) -> Result<usize, c_int> {
+        self.ensure_writable()?;
+
+        let file_size = {
+            let handle = self.fh_map.get(&fh).ok_or(libc::ENOENT)?;
+            handle.ensure_generation(self.device_generation)?;
+            handle.file.metadata().map_err(|_| libc::EIO)?.len()
+        };
+        let offset = offset as u64;
+        self.ensure_capacity((offset + buffer.len() as u64).saturating_sub(file_size))?;
+
+        let record = self.current_faults(Operation::Write).fsync == Some(FsyncFault::FailOnce);
+        let durability = self.config.load().durability;
+
         let logger = &self.logger;
-        let f = self.fh_map.get_mut(&fh).ok_or(libc::ENOENT)?;
-        if let Err(error) = f.seek(SeekFrom::Start(offset as u64)) {
-            error!(self.logger, "seek error {}", error);
-            return Err(libc::EIO);
+        let handle = self.fh_map.get_mut(&fh).ok_or(libc::ENOENT)?;
+        if record {
+            // Keep the overwritten contents to drop the written data on fsync.
+            handle.record(offset, buffer.len()).map_err(|error| {
+                error!(logger, "record error {}", error);
+                libc::EIO
+            })?;
+        } else {
+            // The data written after the fault ends are not dropped.
+            handle.clear_journal();
         }
 
-        let written_size = f.write(buffer).map_err(|error| {
+        let written_size = handle.write_at(buffer, offset).map_err(|error| {
             error!(logger, "write error {}", error);
             libc::EIO
         })?;
 
-        // Reflect the written result to the actual file.
-        let _ = f.sync_all().map_err(|error| {
-            error!(logger, "write error {}", error);
-            libc::EIO
-        })?;
-        let _ = f.sync_data().map_err(|error| {
-            error!(logger, "write error {}", error);
-            libc::EIO
-        })?;
+        if durability == Durability::AlwaysSync {
+            handle.file.sync_data().map_err(|error| {
+                error!(logger, "sync_data error {}", error);
+                libc::EIO
+            })?;
+            handle.clear_journal();
+        }
+
+        self.consume_bytes((offset + written_size as u64).saturating_sub(file_size));
 
         Ok(written_size)
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _: u64,
-        offset: i64,
-        reply: &mut ReplyDirectory,
-    ) -> Result<(), io::Error> {
-        if offset != 0 {
-            // From the FUSE document https://libfuse.github.io/doxygen/structfuse__operations.html#ae269583c4bfaf4d9a82e1d51a902cd5c
-            // Filesystem can ignore the offset.
-            // > 1) The readdir implementation ignores the offset parameter, and passes zero to the filler function's offset.
-            // > The filler function will not return '1' (unless an error happens), so the whole directory is read in a single readdir operation.
-            return Ok(());
-        }
-
-        let files = match self.file_map.get(&ino) {
-            Some(LocalFile::Directory(_, Some(files))) => Ok(files),
-            _ => Err(io::Error::new(io::ErrorKind::NotFound, "")),
-        }?;
-
-        // Add itself and the parent.
-        reply.add(1, 0, FileType::Directory, ".");
-        reply.add(1, 1, FileType::Directory, "..");
-
-        // Add the files in the directory.
-        let mut offset = 2i64;
-        for (fino, _) in files {
-            let (ftype, path) = match self.file_map.get(&fino) {
-                Some(LocalFile::RegularFile(path)) => (FileType::RegularFile, path),
-                Some(LocalFile::Directory(path, _)) => (FileType::Directory, path),
-                None => {
-                    crit!(self.logger, "file_map is inconsistent: {:?}", self.file_map);
-                    crit!(self.logger, "directory ino: {}, file ino: {}", ino, fino);
-                    return Err(io::Error::new(io::ErrorKind::Other, "meybe bug"));
-                }
-            };
-
-            let filename = path
-                .file_name()
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot get filename"))?;
-            reply.add(*fino, offset, ftype, filename);
+    /// Returns the path of the directory after fetching the files in it.
+    fn fetched_dir_path(&mut self, ino: Inode) -> Result<PathBuf, io::Error> {
+        let path = match self.file_map.get(&ino) {
+            Some(LocalFile::Directory(path, _)) => path.clone(),
+            Some(_) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        };
 
-            offset += 1;
-        }
+        self.revalidate_dir(ino, &path)?;
 
-        Ok(())
+        Ok(path)
     }
 
-    fn create(
+    /// Returns the path of `name` in the directory `parent` and its inode if it is known.
+    fn find_child(
         &mut self,
-        _req: &Request,
-        parent: u64,
+        parent: Inode,
         name: &OsStr,
-        _mode: u32,
-        _flags: u32,
-    ) -> Result<(FileAttr, FileHandler), io::Error> {
-        let name = name
-            .to_str()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, ""))?;
+    ) -> Result<(PathBuf, Option<Inode>), io::Error> {
+        let path = self.fetched_dir_path(parent)?.join(name);
+
+        let ino = match self.file_map.get(&parent) {
+            Some(LocalFile::Directory(_, Some(files))) => files
+                .iter()
+                .find(|(_, filename)| filename.as_os_str() == name)
+                .map(|(ino, _)| *ino),
+            _ => None,
+        };
 
-        let (attr, fh, ino, f) = {
-            let (mut path, files) = match self.file_map.get_mut(&parent) {
-                Some(LocalFile::Directory(path, Some(files))) => Ok((path.clone(), files)),
-                _ => Err(io::Error::new(io::ErrorKind::Other, "")),
-            }?;
+        // The directory and the file may be in the different layers.
+        let path = match self.overlay {
+            Some(ref overlay) => overlay.locate(&path, ino.is_some()),
+            None => path,
+        };
 
-            path.push(name);
+        Ok((path, ino))
+    }
 
-            let file = File::create(&path)?;
+    /// Returns the path to create `name` in the directory `parent`.
+    /// In the overlay mode, it is in the upper directory,
+    /// and the second value tells whether a file in the lower layer was removed there.
+    fn creating_path(&mut self, parent: Inode, name: &OsStr) -> Result<(PathBuf, bool), io::Error> {
+        let (path, ino) = self.find_child(parent, name)?;
+        if self.overlay.is_none() {
+            return Ok((path, false));
+        }
 
-            let ino = self.ino_count;
-            self.ino_count += 1;
+        if ino.is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        let removed = self.prepare_upper(&path)?;
+        Ok((path, removed))
+    }
 
-            let attr = fetch_fileattr(ino, &path)?;
+    /// Prepares the parent directories in the upper directory to create the file at the path.
+    /// Returns whether the file in the lower layer was removed there.
+    fn prepare_upper(&mut self, path: &Path) -> Result<bool, io::Error> {
+        let overlay = match self.overlay {
+            Some(ref overlay) => overlay.clone(),
+            None => return Ok(false),
+        };
+        match path.file_name() {
+            Some(name) if !overlay::is_reserved_name(name) => {}
+            _ => return Err(io::Error::from_raw_os_error(libc::EPERM)),
+        }
 
-            let fh = self.fh_count;
-            self.fh_count += 1;
+        for (lower, upper) in overlay.create_parents(path)? {
+            self.switch_to_upper(&lower, &upper)?;
+        }
+        overlay.remove_whiteout(path)
+    }
 
-            self.fh_map.insert(fh, file);
-            files.push((ino, name.into()));
+    /// Copies the file to the upper directory to modify it in the overlay mode.
+    /// Returns the path of the file to modify.
+    fn copy_up(&mut self, ino: Inode) -> Result<PathBuf, io::Error> {
+        let path = match self.file_map.get(&ino) {
+            Some(file) => file.path().clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        };
+        let overlay = match self.overlay {
+            Some(ref overlay) if overlay.is_lower(&path) => overlay.clone(),
+            _ => return Ok(path),
+        };
 
-            (attr, fh, ino, LocalFile::RegularFile(path))
+        for (lower, upper) in overlay.create_parents(&path)? {
+            self.switch_to_upper(&lower, &upper)?;
+        }
+        let upper = match overlay.upper_path(&path).symlink_metadata() {
+            // The directory may have been created as a parent of the other files.
+            Ok(_) => overlay.upper_path(&path),
+            Err(_) => overlay.copy_up(&path)?,
         };
+        info!(self.logger, "copy up: ino: {}, path: {:?}", ino, upper);
+        self.switch_to_upper(&path, &upper)?;
 
-        self.file_map.insert(ino, f);
+        Ok(upper)
+    }
 
-        Ok((attr, fh))
+    /// Lets the file copied to the upper directory keep its inode.
+    fn switch_to_upper(&mut self, lower: &Path, upper: &Path) -> Result<(), io::Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let lower_metadata = fs::symlink_metadata(lower)?;
+        let upper_metadata = fs::symlink_metadata(upper)?;
+        let lower_key = (lower_metadata.dev(), lower_metadata.ino());
+        if let Some(ino) = self.ino_map.get(&lower_key).cloned() {
+            self.map_key((upper_metadata.dev(), upper_metadata.ino()), ino);
+            if let Some(file) = self.file_map.get_mut(&ino) {
+                if file.path() == lower {
+                    *file.path_mut() = upper.into();
+                }
+            }
+        }
+        Ok(())
     }
-}
 
-impl Filesystem for Mizumochi {
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        info!(self.logger, "init");
+    /// Hides the removed file of the lower layer in the overlay mode.
+    fn whiteout(&mut self, path: &Path) -> Result<(), io::Error> {
+        let overlay = match self.overlay {
+            Some(ref overlay) => overlay.clone(),
+            None => return Ok(()),
+        };
 
-        Mizumochi::init(self).map_err(|error| {
-            error!(self.logger, "init error: {}", error);
-            libc::EIO
-        })
+        for (lower, upper) in overlay.create_parents(path)? {
+            self.switch_to_upper(&lower, &upper)?;
+        }
+        overlay.whiteout(path)
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        debug!(self.logger, "lookup: parent: {}, name: {:?}", parent, name);
-        self.metrics.io_operations_lookup.increment();
-
-        match Mizumochi::lookup(self, parent, name) {
-            Ok(ref attr) => reply.entry(&TTL, attr, 0),
-            Err(error) => match error.kind() {
-                io::ErrorKind::NotFound => reply.error(libc::ENOENT),
-                _ => {
-                    error!(self.logger, "lookup error: {}", error);
-                    reply.error(libc::EIO)
-                }
-            },
+    fn add_child(&mut self, parent: Inode, ino: Inode, name: &OsStr) {
+        if let Some(LocalFile::Directory(_, Some(files))) = self.file_map.get_mut(&parent) {
+            files.push((ino, name.into()));
         }
+        self.record_dir_modified(parent);
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        debug!(self.logger, "getattr: ino: {:?}", ino);
-        self.metrics.io_operations_getattr.increment();
+    fn remove_child(&mut self, parent: Inode, ino: Inode, name: &OsStr) {
+        if let Some(LocalFile::Directory(_, Some(files))) = self.file_map.get_mut(&parent) {
+            files.retain(|(fino, filename)| *fino != ino || filename.as_os_str() != name);
+        }
+        self.record_dir_modified(parent);
+    }
 
-        match self.file_map.get(&ino) {
-            Some(LocalFile::RegularFile(path)) => match fetch_fileattr(ino, path) {
-                Ok(attr) => reply.attr(&TTL, &attr),
-                Err(error) => {
-                    error!(
-                        self.logger,
-                        "getattr error: ino = {}, path = {:?}, error = {}", ino, path, error
-                    );
-                    reply.error(libc::EIO)
+    /// Removes the link `name` to the file from the directory `parent`.
+    /// The file is forgotten if it has no other links.
+    fn remove_link(&mut self, parent: Inode, ino: Inode, name: &OsStr, path: &Path) {
+        self.remove_child(parent, ino, name);
+
+        // Find the other link to the file.
+        let other_path = self.file_map.values().find_map(|file| match file {
+            LocalFile::Directory(dir, Some(files)) => files
+                .iter()
+                .find(|(fino, _)| *fino == ino)
+                .map(|(_, filename)| dir.join(filename)),
+            _ => None,
+        });
+
+        match other_path {
+            Some(other_path) => {
+                if let Some(file) = self.file_map.get_mut(&ino) {
+                    if file.path() == path {
+                        *file.path_mut() = other_path;
+                    }
                 }
-            },
-            Some(LocalFile::Directory(path, _)) => match fetch_fileattr(ino, path) {
-                Ok(attr) => reply.attr(&TTL, &attr),
-                Err(error) => {
-                    error!(
-                        self.logger,
-                        "getattr error: ino = {}, path = {:?}, error = {}", ino, path, error
-                    );
-                    reply.error(libc::EIO)
+            }
+            // The removed file can be used until it is closed.
+            None if self.is_open(ino) => {
+                self.unlinked.insert(ino);
+            }
+            None => {
+                self.unmap_keys(ino);
+                self.dir_checked.remove(&ino);
+
+                // The files in the removed directory are forgotten as well.
+                if let Some(LocalFile::Directory(_, Some(files))) = self.file_map.remove(&ino) {
+                    for (fino, filename) in files {
+                        self.remove_link(ino, fino, filename.as_os_str(), &path.join(&filename));
+                    }
                 }
-            },
-            _ => {
-                reply.error(libc::ENOENT);
             }
         }
     }
 
-    fn readdir(
-        &mut self,
-        req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        debug!(
-            self.logger,
-            "readdir: ino: {}, fh: {}, offset: {}", ino, fh, offset
-        );
-        self.metrics.io_operations_readdir.increment();
+    fn is_open(&self, ino: Inode) -> bool {
+        self.fh_map.values().any(|handle| handle.ino == ino)
+    }
 
-        use self::io::ErrorKind;
-        if let Err(error) = self.readdir(req, ino, fh, offset, &mut reply) {
-            let e = match error.kind() {
-                ErrorKind::NotFound => libc::ENOENT,
-                _ => {
-                    error!(self.logger, "readdir error: {}", error);
-                    libc::EIO
+    /// Returns the attributes of the file.
+    /// The removed files still open are inspected via their handles.
+    fn fetch_attr(&self, ino: Inode) -> Result<FileAttr, io::Error> {
+        if self.unlinked.contains(&ino) {
+            let handle = self.fh_map.values().find(|handle| handle.ino == ino);
+            if let Some(handle) = handle {
+                return fileattr_from(ino, &handle.file.metadata()?);
+            }
+        }
+
+        match self.file_map.get(&ino) {
+            Some(file) => fetch_fileattr(ino, file.path()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        }
+    }
+
+    /// Closes the file.
+    /// The file is forgotten if it has been removed and no other handles remain.
+    fn close(&mut self, fh: FileHandler) -> Option<Handle> {
+        let handle = self.fh_map.remove(&fh)?;
+        let ino = handle.ino;
+        if self.unlinked.contains(&ino) && !self.is_open(ino) {
+            self.unlinked.remove(&ino);
+            self.file_map.remove(&ino);
+            self.unmap_keys(ino);
+        }
+        Some(handle)
+    }
+
+    /// Returns the lock which prevents the given lock, if any.
+    fn getlk(&self, ino: Inode, lock: &Lock) -> Option<Lock> {
+        self.locks.find_conflict(ino, lock).cloned()
+    }
+
+    /// Acquires or releases the lock.
+    /// Returns `false` if the lock must be waited for until the conflicting locks are released.
+    fn setlk(&mut self, ino: Inode, lock: Lock, wait: bool) -> Result<bool, c_int> {
+        if lock.typ != libc::F_UNLCK {
+            match self.current_faults(Operation::Write).lock {
+                Some(LockFault::Delay(duration)) => self.shutdown.sleep(duration),
+                Some(LockFault::Deny) => {
+                    info!(self.logger, "lock fault: deny the lock, ino: {}", ino);
+                    return Err(libc::EAGAIN);
                 }
-            };
+                None => {}
+            }
+        }
 
-            reply.error(e);
-        } else {
+        if wait && self.locks.find_conflict(ino, &lock).is_some() {
+            if self.lock_waiters.would_deadlock(&self.locks, ino, &lock) {
+                return Err(libc::EDEADLK);
+            }
+            return Ok(false);
+        }
+
+        self.locks.set(ino, lock).map(|()| true)
+    }
+
+    /// Replies to the requests waiting for the locks which are no longer blocked.
+    fn wake_lock_waiters(&mut self) {
+        for reply in self.lock_waiters.acquire(&mut self.locks) {
             reply.ok();
         }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        reply: ReplyData,
-    ) {
-        debug!(
-            self.logger,
-            "read: ino: {}, fh: {}, offset: {}, size: {}", ino, fh, offset, size
+    /// Returns the statistics of the file system of the original directory
+    /// overridden by the config.
+    fn statfs(&mut self) -> Result<Statfs, io::Error> {
+        let st = statvfs(&self.original_dir)?;
+        // The types of the fields differ among the platforms.
+        #[allow(clippy::useless_conversion)]
+        let (block_size, mut blocks, mut bfree, mut bavail, mut files, mut ffree) = (
+            u64::from(st.f_frsize).max(1),
+            u64::from(st.f_blocks),
+            u64::from(st.f_bfree),
+            u64::from(st.f_bavail),
+            u64::from(st.f_files),
+            u64::from(st.f_ffree),
         );
-        self.metrics.io_operations_read.increment();
 
-        let start = PreciseTime::now();
+        let (capacity, max_files, overrides) = {
+            let config = self.config.load();
+            (
+                config.capacity.as_ref().map(|c| c.bytes),
+                config.max_files,
+                config.statfs.clone(),
+            )
+        };
 
-        let mut buffer = vec![0; size as usize];
+        if let Some(total) = overrides.total_bytes {
+            blocks = total / block_size;
+        }
+        if let Some(free) = overrides.free_bytes {
+            bfree = free / block_size;
+            bavail = bfree;
+        }
+        if let Some(total) = overrides.total_files {
+            files = total;
+        }
+        if let Some(free) = overrides.free_files {
+            ffree = free;
+        }
+        if let Some(ratio) = overrides.free_ratio {
+            let scale = |n: u64| (n as f64 * ratio.max(0.0)) as u64;
+            bfree = scale(bfree);
+            bavail = scale(bavail);
+            ffree = scale(ffree);
+        }
+        bfree = bfree.min(blocks);
+        bavail = bavail.min(bfree);
+        ffree = ffree.min(files);
+
+        if let Some(capacity) = capacity {
+            let free = self.free_bytes()?;
+            blocks = capacity / block_size;
+            // The whole capacity is free until the usage is computed.
+            bfree = free.unwrap_or(capacity) / block_size;
+            bavail = bfree;
+        }
 
-        match Mizumochi::read(self, fh, &mut buffer, offset, size) {
-            Ok(read_size) => {
-                reply.data(&buffer[0..read_size]);
+        // Count the files mizumochi knows as the used inodes.
+        if let Some(max_files) = max_files {
+            files = max_files;
+            ffree = max_files.saturating_sub(self.file_map.len() as u64);
+        }
 
-                if State::Unstable == *self.change_state_if_necessary(Operation::Read) {
-                    if let Speed::Bps(bps) = self.config.load().speed {
-                        // Mesure elapsed time and wait if necessary.
-                        sleep(compute_sleep_duration_to_adjust_speed(
-                            bps,
-                            read_size,
-                            start.to(PreciseTime::now()).num_milliseconds() as u64,
-                        ));
-                    }
-                }
-            }
-            Err(error) => {
-                error!(self.logger, "read error: {}", error);
-                reply.error(libc::EIO);
-            }
+        // `ST_RDONLY` cannot be replied via FUSE.
+        // Instead, no free space is reported while the mountpoint is read-only.
+        if self.current_faults(Operation::Read).read_only {
+            bavail = 0;
+            ffree = 0;
         }
+
+        Ok(Statfs {
+            blocks,
+            bfree,
+            bavail,
+            files,
+            ffree,
+            bsize: st.f_bsize as u32,
+            namelen: st.f_namemax as u32,
+            frsize: block_size as u32,
+        })
     }
 
-    fn setattr(
+    fn mkdir(&mut self, parent: Inode, name: &OsStr, mode: u32) -> Result<FileAttr, io::Error> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        self.ensure_file_slot()
+            .map_err(io::Error::from_raw_os_error)?;
+
+        let (path, removed) = self.creating_path(parent, name)?;
+        fs::DirBuilder::new().mode(mode).create(&path)?;
+        if let (Some(ref overlay), true) = (&self.overlay, removed) {
+            // Hide the contents of the removed directory in the lower layer.
+            overlay.make_opaque(&path)?;
+        }
+
+        let ino = self.assign_ino(&fs::symlink_metadata(&path)?);
+
+        // The new directory is empty.
+        self.file_map
+            .insert(ino, LocalFile::Directory(path.clone(), Some(Vec::new())));
+        self.add_child(parent, ino, name);
+
+        fetch_fileattr(ino, &path)
+    }
+
+    fn mknod(
         &mut self,
-        _req: &Request,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
-        fh: Option<u64>,
-        _crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        debug!(self.logger, "setattr: ino: {}, fh: {:?}", ino, fh);
-        self.metrics.io_operations_setattr.increment();
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<FileAttr, io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        self.ensure_file_slot()
+            .map_err(io::Error::from_raw_os_error)?;
+
+        let (path, _) = self.creating_path(parent, name)?;
+        let cpath = cstring_from(&path)?;
+        if unsafe { libc::mknod(cpath.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
 
-        match self.file_map.get(&ino) {
-            Some(LocalFile::RegularFile(path)) => match fetch_fileattr(ino, path) {
-                Ok(attr) => reply.attr(&TTL, &attr),
-                Err(error) => {
-                    error!(
-                        self.logger,
-                        "getattr error: ino = {}, path = {:?}, error = {}", ino, path, error
-                    );
-                    reply.error(libc::EIO)
+        let metadata = fs::symlink_metadata(&path)?;
+        let ino = self.assign_ino(&metadata);
+        self.file_map
+            .insert(ino, LocalFile::from_metadata(path.clone(), &metadata));
+        self.add_child(parent, ino, name);
+
+        fetch_fileattr(ino, &path)
+    }
+
+    fn unlink(&mut self, parent: Inode, name: &OsStr) -> Result<(), io::Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        let (path, ino) = self.find_child(parent, name)?;
+
+        let metadata = fs::symlink_metadata(&path)?;
+        match self.overlay {
+            Some(ref overlay) if overlay.is_lower(&path) => {}
+            _ => fs::remove_file(&path)?,
+        }
+        self.whiteout(&path)?;
+
+        if metadata.is_file() && metadata.nlink() == 1 {
+            self.release_bytes(metadata.len());
+        }
+
+        if let Some(ino) = ino {
+            self.remove_link(parent, ino, name, &path);
+        }
+
+        Ok(())
+    }
+
+    fn rmdir(&mut self, parent: Inode, name: &OsStr) -> Result<(), io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        let (path, ino) = self.find_child(parent, name)?;
+
+        match self.overlay.clone() {
+            Some(overlay) => {
+                // The directory in the upper directory may have the whiteouts.
+                if !self.list_dir(&path)?.is_empty() {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
                 }
-            },
-            Some(LocalFile::Directory(path, _)) => match fetch_fileattr(ino, path) {
-                Ok(attr) => reply.attr(&TTL, &attr),
-                Err(error) => {
-                    error!(
-                        self.logger,
-                        "getattr error: ino = {}, path = {:?}, error = {}", ino, path, error
-                    );
-                    reply.error(libc::EIO)
+                let upper = overlay.upper_path(&path);
+                if upper.symlink_metadata().is_ok() {
+                    fs::remove_dir_all(&upper)?;
                 }
-            },
-            _ => {
-                reply.error(libc::ENOENT);
+                self.whiteout(&path)?;
             }
+            None => fs::remove_dir(&path)?,
         }
+
+        if let Some(ino) = ino {
+            self.remove_link(parent, ino, name, &path);
+        }
+
+        Ok(())
     }
 
-    fn write(
+    fn rename(
         &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _flags: u32,
-        reply: ReplyWrite,
-    ) {
-        debug!(
-            self.logger,
-            "write: ino: {}, fh: {}, offset: {}, size: {}",
-            ino,
-            fh,
-            offset,
-            data.len()
-        );
-        self.metrics.io_operations_write.increment();
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+    ) -> Result<(), io::Error> {
+        use std::os::unix::fs::MetadataExt;
 
-        let start = PreciseTime::now();
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        let (mut src, ino) = self.find_child(parent, name)?;
+        let (mut dst, replaced) = self.find_child(newparent, newname)?;
 
-        match Mizumochi::write(self, fh, data, offset) {
-            Ok(written_size) => {
-                reply.written(written_size as u32);
+        // Renaming a file to its hard link does nothing (see rename(2)).
+        if replaced.is_some() && replaced == ino {
+            return Ok(());
+        }
 
-                if State::Unstable == *self.change_state_if_necessary(Operation::Write) {
-                    if let Speed::Bps(bps) = self.config.load().speed {
-                        sleep(compute_sleep_duration_to_adjust_speed(
-                            bps,
-                            written_size,
-                            start.to(PreciseTime::now()).num_milliseconds() as u64,
-                        ));
-                    }
-                }
+        let replaced_metadata = fs::symlink_metadata(&dst).ok();
+        let mut removed = false;
+        if let Some(overlay) = self.overlay.clone() {
+            let is_dir = fs::symlink_metadata(&src)?.is_dir();
+            // The directories are not copied with their contents.
+            if is_dir && overlay.merges_lower(&src) {
+                return Err(io::Error::from_raw_os_error(libc::EXDEV));
             }
-            Err(ecode) => {
-                error!(self.logger, "  read error: {:?}", ecode);
-                reply.error(ecode);
+
+            // The directory replaced in the lower layer must be empty as well.
+            let replaced_lower = replaced_metadata.is_some() && overlay.is_lower(&dst);
+            if is_dir && replaced_lower && !self.list_dir(&dst)?.is_empty() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
             }
-        }
-    }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        // TODO: handle the flags.
-        info!(self.logger, "open ino: {}, flags: {}", ino, flags);
-        self.metrics.io_operations_open.increment();
+            src = match ino {
+                Some(ino) => self.copy_up(ino)?,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+            };
+            dst = overlay.upper_path(&dst);
+            let whited_out = self.prepare_upper(&dst)?;
+            removed = is_dir && (whited_out || replaced_lower);
+        }
+        fs::rename(&src, &dst)?;
+        self.whiteout(&src)?;
+        if let (Some(ref overlay), true) = (&self.overlay, removed) {
+            overlay.make_opaque(&dst)?;
+        }
 
-        match self.file_map.get(&ino) {
-            Some(LocalFile::RegularFile(filepath)) => {
-                let mut options = fs::OpenOptions::new();
-                options.read(true).write(true).create(false);
-
-                info!(self.logger, "filepath: {:?}", filepath);
-                match options.open(filepath) {
-                    Ok(f) => {
-                        let fh = self.fh_count;
-                        self.fh_count += 1;
-                        self.fh_map.insert(fh, f);
-
-                        reply.opened(fh, 0);
-                    }
-                    Err(error) => {
-                        error!(self.logger, "open error: {}", error);
-                        reply.error(libc::EIO)
-                    }
+        // The file at the destination is overwritten.
+        if let Some(replaced) = replaced.filter(|replaced| Some(*replaced) != ino) {
+            if let Some(metadata) = replaced_metadata {
+                if metadata.is_file() && metadata.nlink() == 1 {
+                    self.release_bytes(metadata.len());
                 }
             }
-            Some(LocalFile::Directory(filepath, _)) => {
-                error!(self.logger, "directory: {:?}", filepath);
-                reply.error(libc::ENOENT)
-            }
-            None => {
-                error!(self.logger, "readdir error: inode {} is not found", ino);
-                reply.error(libc::ENOENT)
-            }
+
+            self.remove_link(newparent, replaced, newname, &dst);
         }
-    }
 
-    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        debug!(self.logger, "flush: ino: {}, fh: {}", ino, fh);
-        self.metrics.io_operations_flush.increment();
+        if let Some(ino) = ino {
+            self.remove_child(parent, ino, name);
+            self.add_child(newparent, ino, newname);
+        }
 
-        if let Some(f) = self.fh_map.get_mut(&fh) {
-            if let Err(error) = f.seek(SeekFrom::Start(0)) {
-                info!(self.logger, "flush seek error: {}", error);
-                reply.error(libc::EIO);
-            } else {
-                reply.ok();
+        // Update the paths of the file and the files under it.
+        for file in self.file_map.values_mut() {
+            if let Some(path) = replace_path_prefix(file.path(), &src, &dst) {
+                *file.path_mut() = path;
             }
-        } else {
-            error!(self.logger, "flush error: no entry");
-            reply.error(libc::ENOENT);
         }
+
+        Ok(())
     }
 
-    fn release(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        info!(self.logger, "release: ino: {}, fh: {}", ino, fh);
-        self.metrics.io_operations_release.increment();
+    fn symlink(&mut self, parent: Inode, name: &OsStr, link: &Path) -> Result<FileAttr, io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        self.ensure_file_slot()
+            .map_err(io::Error::from_raw_os_error)?;
 
-        if let Some(f) = self.fh_map.remove(&fh) {
-            if let Err(error) = f.sync_data() {
-                error!(self.logger, "sync_data error: {}", error);
-                reply.error(libc::EIO);
-            } else {
-                reply.ok();
-            }
-        } else {
-            error!(self.logger, "release error: no entry");
-            reply.error(libc::ENOENT);
-        }
-    }
+        let (path, _) = self.creating_path(parent, name)?;
+        std::os::unix::fs::symlink(link, &path)?;
 
-    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
-        debug!(
-            self.logger,
-            "fsync ino: {}, fh: {}, datasync: {}", ino, fh, datasync
-        );
-        self.metrics.io_operations_fsync.increment();
+        let ino = self.assign_ino(&fs::symlink_metadata(&path)?);
 
-        if let Some(f) = self.fh_map.get(&fh) {
-            if let Err(error) = f.sync_data() {
-                error!(self.logger, "sync_data error: {}", error);
-                reply.error(libc::EIO);
-            } else {
-                reply.ok();
-            }
-        } else {
-            error!(self.logger, "fsync error: no entry");
-            reply.error(libc::ENOENT);
+        self.file_map.insert(ino, LocalFile::Symlink(path.clone()));
+        self.add_child(parent, ino, name);
+
+        fetch_fileattr(ino, &path)
+    }
+
+    fn readlink(&self, ino: Inode) -> Result<PathBuf, io::Error> {
+        match self.file_map.get(&ino) {
+            Some(LocalFile::Symlink(path)) => fs::read_link(path),
+            Some(_) => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "")),
         }
     }
 
-    fn getxattr(
+    fn link(
         &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
-        reply: ReplyXattr,
-    ) {
-        debug!(self.logger, "getxattr");
-        self.metrics.io_operations_getxattr.increment();
-
-        reply.error(libc::ENOSYS);
-    }
+        ino: Inode,
+        newparent: Inode,
+        newname: &OsStr,
+    ) -> Result<FileAttr, io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        match self.file_map.get(&ino) {
+            Some(LocalFile::Directory(..)) => {
+                return Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+            Some(_) => {}
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        }
 
-    fn destroy(&mut self, _req: &Request) {
-        debug!(self.logger, "destroy");
-        self.metrics.io_operations_destroy.increment();
-    }
+        let (dst, _) = self.creating_path(newparent, newname)?;
+        let src = self.copy_up(ino)?;
+        fs::hard_link(&src, &dst)?;
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
-        debug!(self.logger, "forget");
-        self.metrics.io_operations_forget.increment();
-    }
+        self.add_child(newparent, ino, newname);
 
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
-        debug!(self.logger, "readlink");
-        self.metrics.io_operations_readlink.increment();
-        reply.error(libc::ENOSYS);
+        fetch_fileattr(ino, &dst)
     }
 
-    fn mknod(
+    /// Applies the given attributes to the file.
+    /// The opened file is used if `fh` is given.
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
         &mut self,
-        _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!(self.logger, "mknod");
-        self.metrics.io_operations_mknod.increment();
-        reply.error(libc::ENOSYS);
-    }
+        ino: Inode,
+        fh: Option<u64>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+    ) -> Result<FileAttr, io::Error> {
+        use std::os::unix::fs::{fchown, lchown, PermissionsExt};
+
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+        let (path, is_symlink) = match self.file_map.get(&ino) {
+            Some(file) => (file.path().clone(), matches!(file, LocalFile::Symlink(_))),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        };
+        let unlinked = self.unlinked.contains(&ino);
+        let changed = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || size.is_some()
+            || atime.is_some()
+            || mtime.is_some();
+        let path = match self.overlay {
+            // The removed file cannot be copied up.
+            Some(ref overlay) if unlinked && overlay.is_lower(&path) => {
+                return Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+            _ if changed && !unlinked => self.copy_up(ino)?,
+            _ => path,
+        };
 
-    fn mkdir(
-        &mut self,
-        _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!(self.logger, "mkdir");
-        self.metrics.io_operations_mkdir.increment();
-        reply.error(libc::ENOSYS);
-    }
+        let handle = match fh {
+            Some(fh) => Some(
+                self.fh_map
+                    .get(&fh)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?,
+            ),
+            // The removed file is changed via any of its handles.
+            None if unlinked => self.fh_map.values().find(|handle| handle.ino == ino),
+            None => None,
+        };
+        let file = match handle {
+            Some(handle) => {
+                handle
+                    .ensure_generation(self.device_generation)
+                    .map_err(io::Error::from_raw_os_error)?;
+                Some(handle.file.try_clone()?)
+            }
+            None => None,
+        };
+        // The file may have been opened in the lower layer.
+        let file = file.filter(|_| self.overlay.is_none() || unlinked);
+
+        // The permissions of symbolic links are not used.
+        if let (Some(mode), false) = (mode, is_symlink) {
+            let permissions = fs::Permissions::from_mode(mode);
+            match file {
+                Some(ref file) => file.set_permissions(permissions)?,
+                None => fs::set_permissions(&path, permissions)?,
+            }
+        }
 
-    fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!(self.logger, "unlink");
-        self.metrics.io_operations_unlink.increment();
-        reply.error(libc::ENOSYS);
-    }
+        if uid.is_some() || gid.is_some() {
+            match file {
+                Some(ref file) => fchown(file, uid, gid)?,
+                None => lchown(&path, uid, gid)?,
+            }
+        }
 
-    fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!(self.logger, "rmdir");
-        self.metrics.io_operations_rmdir.increment();
-        reply.error(libc::ENOSYS);
-    }
+        if let Some(size) = size {
+            let file_size = match file {
+                Some(ref file) => file.metadata()?.len(),
+                None => fs::symlink_metadata(&path)?.len(),
+            };
+            self.ensure_capacity(size.saturating_sub(file_size))
+                .map_err(io::Error::from_raw_os_error)?;
 
-    fn symlink(
-        &mut self,
-        _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _link: &Path,
-        reply: ReplyEntry,
-    ) {
-        debug!(self.logger, "symlink");
-        self.metrics.io_operations_symlink.increment();
-        reply.error(libc::ENOSYS);
-    }
+            match self.current_faults(Operation::Write).truncate {
+                Some(TruncateFault::IoError) => {
+                    info!(self.logger, "truncate fault: ino: {}", ino);
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                Some(TruncateFault::Ignore) => {
+                    info!(self.logger, "truncate fault: ignore, ino: {}", ino);
+                }
+                None => {
+                    match file {
+                        Some(ref file) => file.set_len(size)?,
+                        None => fs::OpenOptions::new()
+                            .write(true)
+                            .open(&path)?
+                            .set_len(size)?,
+                    }
 
-    fn rename(
-        &mut self,
-        _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _newparent: u64,
-        _newname: &OsStr,
-        reply: ReplyEmpty,
-    ) {
-        debug!(self.logger, "rename");
-        self.metrics.io_operations_rename.increment();
-        reply.error(libc::ENOSYS);
-    }
+                    if file_size < size {
+                        self.consume_bytes(size - file_size);
+                    } else {
+                        self.release_bytes(file_size - size);
+                    }
+                }
+            }
+        }
 
-    fn link(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &OsStr,
-        reply: ReplyEntry,
-    ) {
-        debug!(self.logger, "link");
-        self.metrics.io_operations_link.increment();
-        reply.error(libc::ENOSYS);
+        if atime.is_some() || mtime.is_some() {
+            set_file_times(&path, file.as_ref(), atime, mtime)?;
+        }
+
+        self.fetch_attr(ino)
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
-        debug!(self.logger, "opendir: ino: {}", ino);
-        self.metrics.io_operations_opendir.increment();
+    /// Returns the entries of the directory including itself and the parent.
+    fn list_entries(&mut self, ino: Inode) -> Result<Vec<(Inode, FileType, PathBuf)>, io::Error> {
+        // Reflect the changes by the others at the beginning of the listing.
+        let path = self.fetched_dir_path(ino)?;
+        let parent = self.parent_ino(ino, &path);
+
+        let files = match self.file_map.get(&ino) {
+            Some(LocalFile::Directory(_, Some(files))) => files,
+            _ => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        };
+
+        let mut entries = vec![
+            (ino, FileType::Directory, PathBuf::from(".")),
+            (parent, FileType::Directory, PathBuf::from("..")),
+        ];
+        for (fino, name) in files {
+            match self.file_map.get(fino) {
+                Some(file) => entries.push((*fino, file.kind(), name.clone())),
+                None => {
+                    crit!(self.logger, "file_map is inconsistent: {:?}", self.file_map);
+                    crit!(self.logger, "directory ino: {}, file ino: {}", ino, fino);
+                    return Err(io::Error::new(io::ErrorKind::Other, "meybe bug"));
+                }
+            }
+        }
 
-        reply.opened(0, 0);
+        Ok(entries)
     }
 
-    fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
-        debug!(self.logger, "releasedir");
-        self.metrics.io_operations_releasedir.increment();
-        reply.ok();
+    fn opendir(&mut self, ino: Inode) -> Result<FileHandler, io::Error> {
+        match self.file_map.get(&ino) {
+            Some(LocalFile::Directory(..)) => {}
+            Some(_) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        }
+
+        let fh = self.fh_count;
+        self.fh_count += 1;
+
+        Ok(fh)
     }
 
-    fn fsyncdir(
+    /// Adds the entries from the offset until `add` returns `true` (the buffer is full).
+    fn readdir<F>(
         &mut self,
-        _req: &Request,
-        _ino: u64,
-        _fh: u64,
-        _datasync: bool,
-        reply: ReplyEmpty,
-    ) {
-        debug!(self.logger, "fsyncdir");
-        self.metrics.io_operations_fsyncdir.increment();
-        reply.error(libc::ENOSYS);
-    }
+        ino: Inode,
+        fh: FileHandler,
+        offset: i64,
+        mut add: F,
+    ) -> Result<(), io::Error>
+    where
+        F: FnMut(Inode, i64, FileType, &OsStr) -> bool,
+    {
+        // The entries are listed at the beginning, and the listing resumes from them
+        // so that the entries are neither skipped nor repeated even if the directory changes.
+        let resuming = offset != 0 && self.dir_entries.contains_key(&fh);
+        if !resuming {
+            let entries = self.list_entries(ino)?;
+            self.dir_entries.insert(fh, entries);
+        }
+        let delay = self.current_faults(Operation::Read).readdir_delay;
+
+        // The offset of each entry is the index of the next entry.
+        let entries = &self.dir_entries[&fh];
+        for (index, (fino, kind, name)) in entries.iter().enumerate().skip(offset.max(0) as usize) {
+            // The buffer is full.
+            if add(*fino, index as i64 + 1, *kind, name.as_os_str()) {
+                break;
+            }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        // debug!(self.logger, "statfs");
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
-        self.metrics.io_operations_statfs.increment();
+            if let Some(delay) = delay {
+                self.shutdown.sleep(delay);
+            }
+        }
+
+        Ok(())
     }
 
     fn setxattr(
         &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
-        _flags: u32,
-        _position: u32,
-        reply: ReplyEmpty,
-    ) {
-        debug!(self.logger, "setxattr");
-        self.metrics.io_operations_setxattr.increment();
-        reply.error(libc::ENOSYS);
-    }
+        ino: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
 
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
-        debug!(self.logger, "listxattr");
-        self.metrics.io_operations_listxattr.increment();
-        reply.error(libc::ENOSYS);
+        if let Some(XattrFault::NotSupported) = self.current_faults(Operation::Write).xattr {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+
+        let path = self.copy_up(ino)?;
+        xattr::set(&path, name, value, flags as c_int)
     }
 
-    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!(self.logger, "removexattr");
-        self.metrics.io_operations_removexattr.increment();
-        reply.error(libc::ENOSYS);
+    fn removexattr(&mut self, ino: Inode, name: &OsStr) -> Result<(), io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+
+        if let Some(fault) = self.current_faults(Operation::Write).xattr {
+            return Err(io::Error::from_raw_os_error(fault.errno()));
+        }
+
+        let path = self.copy_up(ino)?;
+        xattr::remove(&path, name)
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-        debug!(self.logger, "access");
-        self.metrics.io_operations_access.increment();
-        reply.error(libc::ENOSYS);
+    /// Checks the caller can access the file with the given mask.
+    fn access(&mut self, caller: &Caller, ino: Inode, mask: u32) -> Result<(), c_int> {
+        if mask & permission::W_OK != 0 {
+            self.ensure_writable()?;
+        } else {
+            self.ensure_device_present()?;
+        }
+
+        let path = match self.file_map.get(&ino) {
+            Some(file) => file.path().to_path_buf(),
+            None => return Err(libc::ENOENT),
+        };
+
+        // The paths are matched on the mountpoint regardless of the layer which has the file.
+        let relative = match self.overlay {
+            Some(ref overlay) => overlay.relative(&path),
+            None => path.strip_prefix(&self.original_dir).unwrap_or(&path),
+        };
+        let denied = self
+            .current_faults(Operation::Read)
+            .deny_access
+            .iter()
+            .any(|denied| relative.starts_with(denied.strip_prefix("/").unwrap_or(denied)));
+        if denied {
+            info!(self.logger, "access fault: deny {:?}", path);
+            return Err(libc::EACCES);
+        }
+
+        let attr = fetch_fileattr(ino, &path).map_err(|error| errno_from(&error))?;
+        caller.check(&attr, mask)
     }
 
     fn create(
         &mut self,
-        req: &Request,
+        caller: &Caller,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32,
-        reply: ReplyCreate,
-    ) {
-        debug!(self.logger, "create: parent: {}, name: {:?}", parent, name);
-        self.metrics.io_operations_create.increment();
+    ) -> Result<(FileAttr, FileHandler), io::Error> {
+        self.ensure_writable()
+            .map_err(io::Error::from_raw_os_error)?;
+
+        // A new file cannot be created on the full disk.
+        self.ensure_capacity(1)
+            .map_err(io::Error::from_raw_os_error)?;
+        self.ensure_file_slot()
+            .map_err(io::Error::from_raw_os_error)?;
+
+        let parent_path = self.fetched_dir_path(parent)?;
+        let (path, existing) = self.find_child(parent, name)?;
+
+        match existing {
+            Some(ino) => caller.check(
+                &fetch_fileattr(ino, &path)?,
+                permission::mask_from_open_flags(flags),
+            ),
+            None => caller.check(
+                &fetch_fileattr(parent, &parent_path)?,
+                permission::W_OK | permission::X_OK,
+            ),
+        }
+        .map_err(io::Error::from_raw_os_error)?;
 
-        match Mizumochi::create(self, req, parent, name, mode, flags) {
-            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
-            Err(error) => {
-                error!(self.logger, "init error: {}", error);
-                reply.error(libc::EIO)
+        let path = match existing {
+            Some(ino) => self.copy_up(ino)?,
+            None => {
+                self.prepare_upper(&path)?;
+                path
             }
+        };
+
+        let file_size = fs::metadata(&path).map(|m| m.len()).ok();
+        let file = open_file(&path, flags | libc::O_CREAT as u32, mode & 0o7777)?;
+        if let (Some(file_size), true) = (file_size, flags & libc::O_TRUNC as u32 != 0) {
+            self.release_bytes(file_size);
         }
-    }
 
-    fn getlk(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
-        _pid: u32,
-        reply: ReplyLock,
-    ) {
-        debug!(self.logger, "getlk");
-        self.metrics.io_operations_getlk.increment();
-        reply.error(libc::ENOSYS);
-    }
+        // The file may exist already unless `O_EXCL` is given.
+        let ino = match existing {
+            Some(ino) => ino,
+            None => {
+                let ino = self.assign_ino(&fs::symlink_metadata(&path)?);
+                self.file_map
+                    .insert(ino, LocalFile::RegularFile(path.clone()));
+                self.add_child(parent, ino, name);
+                ino
+            }
+        };
 
-    fn setlk(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
-        _pid: u32,
-        _sleep: bool,
-        reply: ReplyEmpty,
-    ) {
-        debug!(self.logger, "setlk");
-        self.metrics.io_operations_setlk.increment();
-        reply.error(libc::ENOSYS);
-    }
+        let attr = fetch_fileattr(ino, &path)?;
 
-    fn bmap(&mut self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
-        debug!(self.logger, "bmap");
-        self.metrics.io_operations_bmap.increment();
-        reply.error(libc::ENOSYS);
-    }
-}
+        let fh = self.fh_count;
+        self.fh_count += 1;
+        self.fh_map
+            .insert(fh, Handle::new(ino, file, self.device_generation));
 
-fn timespec_from(st: &std::time::SystemTime) -> Timespec {
-    if let Ok(dur_since_epoch) = st.duration_since(std::time::UNIX_EPOCH) {
-        Timespec::new(
-            dur_since_epoch.as_secs() as i64,
-            dur_since_epoch.subsec_nanos() as i32,
-        )
-    } else {
-        Timespec::new(0, 0)
+        Ok((attr, fh))
     }
-}
 
-fn fetch_fileattr(ino: u64, filepath: &Path) -> Result<FileAttr, io::Error> {
-    use std::os::unix::fs::MetadataExt;
-    use std::os::unix::fs::PermissionsExt;
+    fn open(&mut self, caller: &Caller, ino: Inode, flags: u32) -> Result<FileHandler, io::Error> {
+        let path = match self.file_map.get(&ino) {
+            Some(LocalFile::RegularFile(path)) => path.clone(),
+            Some(LocalFile::Directory(..)) => {
+                return Err(io::Error::from_raw_os_error(libc::EISDIR))
+            }
+            Some(LocalFile::Symlink(..)) => return Err(io::Error::from_raw_os_error(libc::ELOOP)),
+            // The kernel opens the special files by itself.
+            Some(LocalFile::Special(..)) => return Err(io::Error::from_raw_os_error(libc::ENXIO)),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "")),
+        };
 
-    let metadata = fs::metadata(filepath)?;
-    let mode = metadata.permissions().mode();
-    let kind = mode & libc::S_IFMT as u32;
+        let truncate = flags & libc::O_TRUNC as u32 != 0;
+        let writing = truncate || flags as c_int & libc::O_ACCMODE != libc::O_RDONLY;
+        if writing {
+            self.ensure_writable()
+                .map_err(io::Error::from_raw_os_error)?;
+        }
 
-    let default_timespec = Timespec::new(0, 0);
-    let mut attr: FileAttr = unsafe { mem::zeroed() };
-    attr.ino = ino;
-    attr.size = metadata.len();
-    attr.atime = metadata
-        .accessed()
-        .map(|time| timespec_from(&time))
-        .unwrap_or(default_timespec);
-    attr.mtime = metadata
-        .modified()
-        .map(|time| timespec_from(&time))
-        .unwrap_or(default_timespec);
-    attr.ctime = metadata
-        .created()
-        .map(|time| timespec_from(&time))
-        .unwrap_or(default_timespec);
-    attr.kind = if kind == libc::S_IFREG as u32 {
-        FileType::RegularFile
-    } else if kind == libc::S_IFDIR as u32 {
-        FileType::Directory
-    } else if kind == libc::S_IFIFO as u32 {
-        FileType::NamedPipe
-    } else if kind == libc::S_IFCHR as u32 {
-        FileType::CharDevice
-    } else if kind == libc::S_IFBLK as u32 {
-        FileType::BlockDevice
-    } else if kind == libc::S_IFLNK as u32 {
-        FileType::Symlink
-    } else if kind == libc::S_IFSOCK as u32 {
-        FileType::Socket
-    } else {
-        return Err(io::Error::new(io::ErrorKind::Other, "unknown kind"));
-    };
-    attr.perm = (mode & (libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO) as u32) as u16;
-    attr.uid = metadata.uid();
-    attr.gid = metadata.gid();
-    attr.nlink = metadata.nlink() as u32;
+        caller
+            .check(
+                &fetch_fileattr(ino, &path)?,
+                permission::mask_from_open_flags(flags),
+            )
+            .map_err(io::Error::from_raw_os_error)?;
 
-    Ok(attr)
-}
+        let path = if writing { self.copy_up(ino)? } else { path };
+        let file_size = if truncate {
+            fs::metadata(&path)?.len()
+        } else {
+            0
+        };
 
-/// `request_bps` means request Byte per seconds (not bit).
-/// `count_byte` is the number of read/written bytes.
-/// `elapsed_ms` is the elapsed time in milliseconds to read/write data.
-fn compute_sleep_duration_to_adjust_speed(
-    request_bps: usize,
-    count_byte: usize,
-    elapsed_ms: u64,
-) -> Duration {
-    if request_bps == 0 {
-        panic!("The given request bps is zero.");
-    }
+        let file = open_file(&path, flags, 0)?;
+        if truncate {
+            self.release_bytes(file_size);
+        }
 
-    let expect_sec = count_byte as f64 / request_bps as f64;
-    let expect_ms = (expect_sec * 1000.0).round() as u64;
-    let wait_ms = expect_ms.saturating_sub(elapsed_ms);
+        let fh = self.fh_count;
+        self.fh_count += 1;
+        self.fh_map
+            .insert(fh, Handle::new(ino, file, self.device_generation));
 
-    Duration::from_millis(wait_ms as u64)
+        Ok(fh)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Drop for Mizumochi {
+    /// Syncs the files still open when the FUSE session ends (e.g., at the shutdown).
+    fn drop(&mut self) {
+        if self.config.load().durability == Durability::NeverSync {
+            return;
+        }
+
+        for (fh, handle) in self.fh_map.drain() {
+            if let Err(error) = handle.file.sync_data() {
+                error!(self.logger, "sync_data error: fh: {}, {}", fh, error);
+            }
+        }
+    }
+}
+
+impl Filesystem for Mizumochi {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        info!(self.logger, "init");
+
+        Mizumochi::init(self).map_err(|error| {
+            error!(self.logger, "init error: {}", error);
+            libc::EIO
+        })
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!(self.logger, "lookup: parent: {}, name: {:?}", parent, name);
+        self.metrics.io_operations_lookup.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match Mizumochi::lookup(self, parent, name) {
+            Ok(attr) => self.reply_entry(reply, attr),
+            Err(error) => match error.kind() {
+                io::ErrorKind::NotFound => reply.error(libc::ENOENT),
+                _ => {
+                    error!(self.logger, "lookup error: {}", error);
+                    reply.error(libc::EIO)
+                }
+            },
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        debug!(self.logger, "getattr: ino: {:?}", ino);
+        self.metrics.io_operations_getattr.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match self.fetch_attr(ino) {
+            Ok(attr) => reply.attr(&self.ttl(), &self.fault_timestamps(attr)),
+            // The file may have been removed by the others.
+            Err(error) => {
+                error!(
+                    self.logger,
+                    "getattr error: ino = {}, error = {}", ino, error
+                );
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    // NOTE: `readdirplus` is not supported by `fuse` for now.
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(
+            self.logger,
+            "readdir: ino: {}, fh: {}, offset: {}", ino, fh, offset
+        );
+        self.metrics.io_operations_readdir.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        use self::io::ErrorKind;
+        let result = Mizumochi::readdir(self, ino, fh, offset, |ino, offset, kind, name| {
+            reply.add(ino, offset, kind, name)
+        });
+        if let Err(error) = result {
+            let e = match error.kind() {
+                ErrorKind::NotFound => libc::ENOENT,
+                _ => {
+                    error!(self.logger, "readdir error: {}", error);
+                    libc::EIO
+                }
+            };
+
+            reply.error(e);
+        } else {
+            reply.ok();
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        debug!(
+            self.logger,
+            "read: ino: {}, fh: {}, offset: {}, size: {}", ino, fh, offset, size
+        );
+        self.metrics.io_operations_read.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        let start = PreciseTime::now();
+
+        let mut buffer = vec![0; size as usize];
+
+        match Mizumochi::read(self, fh, &mut buffer, offset, size) {
+            Ok(read_size) => {
+                reply.data(&buffer[0..read_size]);
+
+                if State::Unstable == *self.change_state_if_necessary(Operation::Read) {
+                    if let Speed::Bps(bps) = self.config.load().speed {
+                        // Mesure elapsed time and wait if necessary.
+                        self.shutdown.sleep(compute_sleep_duration_to_adjust_speed(
+                            bps,
+                            read_size,
+                            start.to(PreciseTime::now()).num_milliseconds() as u64,
+                        ));
+                    }
+                }
+            }
+            Err(ecode) => {
+                error!(self.logger, "read error: {}", ecode);
+                reply.error(ecode);
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!(
+            self.logger,
+            "setattr: ino: {}, fh: {:?}, mode: {:?}, uid: {:?}, gid: {:?}, size: {:?}",
+            ino,
+            fh,
+            mode,
+            uid,
+            gid,
+            size
+        );
+        self.metrics.io_operations_setattr.increment();
+
+        match Mizumochi::setattr(self, ino, fh, mode, uid, gid, size, atime, mtime) {
+            Ok(attr) => reply.attr(&self.ttl(), &self.fault_timestamps(attr)),
+            Err(error) => {
+                error!(
+                    self.logger,
+                    "setattr error: ino = {}, error = {}", ino, error
+                );
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            self.logger,
+            "write: ino: {}, fh: {}, offset: {}, size: {}",
+            ino,
+            fh,
+            offset,
+            data.len()
+        );
+        self.metrics.io_operations_write.increment();
+
+        let start = PreciseTime::now();
+
+        match Mizumochi::write(self, fh, data, offset) {
+            Ok(written_size) => {
+                reply.written(written_size as u32);
+
+                if State::Unstable == *self.change_state_if_necessary(Operation::Write) {
+                    if let Speed::Bps(bps) = self.config.load().speed {
+                        self.shutdown.sleep(compute_sleep_duration_to_adjust_speed(
+                            bps,
+                            written_size,
+                            start.to(PreciseTime::now()).num_milliseconds() as u64,
+                        ));
+                    }
+                }
+            }
+            Err(ecode) => {
+                error!(self.logger, "  read error: {:?}", ecode);
+                reply.error(ecode);
+            }
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        info!(self.logger, "open ino: {}, flags: {:#o}", ino, flags);
+        self.metrics.io_operations_open.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match Mizumochi::open(self, &Caller::from_request(req), ino, flags) {
+            Ok(fh) => reply.opened(fh, self.open_flags()),
+            Err(error) => {
+                error!(self.logger, "open error: ino: {}, error: {}", ino, error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!(self.logger, "flush: ino: {}, fh: {}", ino, fh);
+        self.metrics.io_operations_flush.increment();
+
+        // Closing any descriptor releases the locks of the owner (see fcntl(2)).
+        self.locks.release_owner(ino, lock_owner);
+        self.wake_lock_waiters();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        // Nothing to do since the data are written to the original file directly
+        // and the position of the file is never used.
+        if let Some(handle) = self.fh_map.get(&fh) {
+            if let Err(ecode) = handle.ensure_generation(self.device_generation) {
+                reply.error(ecode);
+            } else {
+                reply.ok();
+            }
+        } else {
+            error!(self.logger, "flush error: no entry");
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!(self.logger, "release: ino: {}, fh: {}", ino, fh);
+        self.metrics.io_operations_release.increment();
+
+        self.locks.release_owner(ino, lock_owner);
+        self.wake_lock_waiters();
+
+        // The handle is removed even if the device has disappeared.
+        let present = self.ensure_device_present();
+        let durability = self.config.load().durability;
+
+        if let Some(handle) = self.close(fh) {
+            if let Err(ecode) = present.and(handle.ensure_generation(self.device_generation)) {
+                reply.error(ecode);
+            } else if durability == Durability::NeverSync {
+                reply.ok();
+            } else if let Err(error) = handle.file.sync_data() {
+                error!(self.logger, "sync_data error: {}", error);
+                reply.error(libc::EIO);
+            } else {
+                reply.ok();
+            }
+        } else {
+            error!(self.logger, "release error: no entry");
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!(
+            self.logger,
+            "fsync ino: {}, fh: {}, datasync: {}", ino, fh, datasync
+        );
+        self.metrics.io_operations_fsync.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        let fault = self.current_faults(Operation::Write).fsync;
+        let durability = self.config.load().durability;
+
+        let handle = if let Some(handle) = self.fh_map.get_mut(&fh) {
+            handle
+        } else {
+            error!(self.logger, "fsync error: no entry");
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        try_reply!(reply, handle.ensure_generation(self.device_generation));
+
+        match fault {
+            Some(FsyncFault::FailOnce) => match handle.drop_unsynced() {
+                Ok(true) => {
+                    info!(
+                        self.logger,
+                        "fsync fault: drop the written data, ino: {}", ino
+                    );
+                    reply.error(libc::EIO);
+                    return;
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    error!(self.logger, "drop_unsynced error: {}", error);
+                    reply.error(libc::EIO);
+                    return;
+                }
+            },
+            Some(FsyncFault::Delay(duration)) => self.shutdown.sleep(duration),
+            Some(FsyncFault::Lie) => {
+                reply.ok();
+                return;
+            }
+            None => {}
+        }
+
+        let result = match durability {
+            Durability::NeverSync => Ok(()),
+            _ if datasync => handle.file.sync_data(),
+            _ => handle.file.sync_all(),
+        };
+        if let Err(error) = result {
+            error!(self.logger, "fsync error: {}", error);
+            reply.error(libc::EIO);
+        } else {
+            handle.clear_journal();
+            reply.ok();
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!(
+            self.logger,
+            "getxattr: ino: {}, name: {:?}, size: {}", ino, name, size
+        );
+        self.metrics.io_operations_getxattr.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        if let Some(fault) = self.current_faults(Operation::Read).xattr {
+            reply.error(fault.errno());
+            return;
+        }
+
+        match self.file_map.get(&ino) {
+            Some(file) => reply_xattr(reply, size, |buffer| xattr::get(file.path(), name, buffer)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        debug!(self.logger, "destroy");
+        self.metrics.io_operations_destroy.increment();
+
+        // The locks are never released after unmounting.
+        for reply in self.lock_waiters.drain() {
+            reply.error(libc::EINTR);
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        debug!(self.logger, "forget: ino: {}, nlookup: {}", ino, nlookup);
+        self.metrics.io_operations_forget.increment();
+
+        Mizumochi::forget(self, ino, nlookup);
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        use std::os::unix::ffi::OsStrExt;
+
+        debug!(self.logger, "readlink: ino: {}", ino);
+        self.metrics.io_operations_readlink.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match Mizumochi::readlink(self, ino) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(error) => {
+                error!(self.logger, "readlink error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            self.logger,
+            "mknod: parent: {}, name: {:?}, mode: {:#o}, rdev: {}", parent, name, mode, rdev
+        );
+        self.metrics.io_operations_mknod.increment();
+
+        match Mizumochi::mknod(self, parent, name, mode, rdev) {
+            Ok(attr) => self.reply_entry(reply, attr),
+            Err(error) => {
+                error!(self.logger, "mknod error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        debug!(self.logger, "mkdir: parent: {}, name: {:?}", parent, name);
+        self.metrics.io_operations_mkdir.increment();
+
+        match Mizumochi::mkdir(self, parent, name, mode) {
+            Ok(attr) => self.reply_entry(reply, attr),
+            Err(error) => {
+                error!(self.logger, "mkdir error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!(self.logger, "unlink: parent: {}, name: {:?}", parent, name);
+        self.metrics.io_operations_unlink.increment();
+
+        match Mizumochi::unlink(self, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(error) => {
+                error!(self.logger, "unlink error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!(self.logger, "rmdir: parent: {}, name: {:?}", parent, name);
+        self.metrics.io_operations_rmdir.increment();
+
+        match Mizumochi::rmdir(self, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(error) => {
+                error!(self.logger, "rmdir error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            self.logger,
+            "symlink: parent: {}, name: {:?}, link: {:?}", parent, name, link
+        );
+        self.metrics.io_operations_symlink.increment();
+
+        match Mizumochi::symlink(self, parent, name, link) {
+            Ok(attr) => self.reply_entry(reply, attr),
+            Err(error) => {
+                error!(self.logger, "symlink error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            self.logger,
+            "rename: parent: {}, name: {:?}, newparent: {}, newname: {:?}",
+            parent,
+            name,
+            newparent,
+            newname
+        );
+        self.metrics.io_operations_rename.increment();
+
+        match Mizumochi::rename(self, parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(error) => {
+                error!(self.logger, "rename error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!(
+            self.logger,
+            "link: ino: {}, newparent: {}, newname: {:?}", ino, newparent, newname
+        );
+        self.metrics.io_operations_link.increment();
+
+        match Mizumochi::link(self, ino, newparent, newname) {
+            Ok(attr) => self.reply_entry(reply, attr),
+            Err(error) => {
+                error!(self.logger, "link error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        debug!(self.logger, "opendir: ino: {}", ino);
+        self.metrics.io_operations_opendir.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match Mizumochi::opendir(self, ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(error) => reply.error(errno_from(&error)),
+        }
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        debug!(self.logger, "releasedir");
+        self.metrics.io_operations_releasedir.increment();
+
+        // The state is cleaned up even if the device has disappeared.
+        self.fsyncdir_failed.remove(&ino);
+        self.dir_entries.remove(&fh);
+
+        try_reply!(reply, self.ensure_device_present());
+
+        reply.ok();
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        debug!(self.logger, "fsyncdir: ino: {}", ino);
+        self.metrics.io_operations_fsyncdir.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match self.current_faults(Operation::Write).fsync {
+            Some(FsyncFault::FailOnce) if self.fsyncdir_failed.insert(ino) => {
+                info!(self.logger, "fsyncdir fault: ino: {}", ino);
+                reply.error(libc::EIO);
+                return;
+            }
+            Some(FsyncFault::Delay(duration)) => self.shutdown.sleep(duration),
+            Some(FsyncFault::Lie) => {
+                reply.ok();
+                return;
+            }
+            Some(FsyncFault::FailOnce) | None => {}
+        }
+
+        let path = match self.file_map.get(&ino) {
+            Some(LocalFile::Directory(path, _)) => path,
+            _ => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        if self.config.load().durability == Durability::NeverSync {
+            reply.ok();
+        } else if let Err(error) = File::open(path).and_then(|dir| dir.sync_all()) {
+            error!(self.logger, "fsyncdir error: {}", error);
+            reply.error(libc::EIO);
+        } else {
+            reply.ok();
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        // debug!(self.logger, "statfs");
+        self.metrics.io_operations_statfs.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        match Mizumochi::statfs(self) {
+            Ok(st) => reply.statfs(
+                st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize,
+            ),
+            Err(error) => {
+                error!(self.logger, "statfs error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            self.logger,
+            "setxattr: ino: {}, name: {:?}, size: {}, flags: {}",
+            ino,
+            name,
+            value.len(),
+            flags
+        );
+        self.metrics.io_operations_setxattr.increment();
+
+        match Mizumochi::setxattr(self, ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno_from(&error)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!(self.logger, "listxattr: ino: {}, size: {}", ino, size);
+        self.metrics.io_operations_listxattr.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        let fault = self.current_faults(Operation::Read).xattr;
+        match (self.file_map.get(&ino), fault) {
+            (Some(_), Some(XattrFault::NoData)) => reply_xattr(reply, size, |_| Ok(0)),
+            (Some(_), Some(XattrFault::NotSupported)) => reply.error(libc::ENOTSUP),
+            (Some(file), None) => {
+                reply_xattr(reply, size, |buffer| xattr::list(file.path(), buffer))
+            }
+            (None, _) => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!(self.logger, "removexattr: ino: {}, name: {:?}", ino, name);
+        self.metrics.io_operations_removexattr.increment();
+
+        match Mizumochi::removexattr(self, ino, name) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno_from(&error)),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        debug!(self.logger, "access: ino: {}, mask: {:#o}", ino, mask);
+        self.metrics.io_operations_access.increment();
+
+        match Mizumochi::access(self, &Caller::from_request(req), ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(ecode) => reply.error(ecode),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        debug!(self.logger, "create: parent: {}, name: {:?}", parent, name);
+        self.metrics.io_operations_create.increment();
+
+        match Mizumochi::create(self, &Caller::from_request(req), parent, name, mode, flags) {
+            Ok((attr, fh)) => {
+                self.count_lookup(attr.ino);
+                let attr = self.fault_timestamps(attr);
+                reply.created(&self.ttl(), &attr, 0, fh, self.open_flags())
+            }
+            Err(error) => {
+                error!(self.logger, "create error: {}", error);
+                reply.error(errno_from(&error))
+            }
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        debug!(
+            self.logger,
+            "getlk: ino: {}, fh: {}, owner: {}, range: {}..={}, type: {}",
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ
+        );
+        self.metrics.io_operations_getlk.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ: typ as c_int,
+            pid,
+        };
+        match Mizumochi::getlk(self, ino, &lock) {
+            Some(l) => reply.locked(l.start, l.end, l.typ as u32, l.pid),
+            None => reply.locked(start, end, libc::F_UNLCK as u32, pid),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        wait: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            self.logger,
+            "setlk: ino: {}, fh: {}, owner: {}, range: {}..={}, type: {}, wait: {}",
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            wait
+        );
+        self.metrics.io_operations_setlk.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ: typ as c_int,
+            pid,
+        };
+        match Mizumochi::setlk(self, ino, lock.clone(), wait) {
+            Ok(true) => {
+                reply.ok();
+                // Releasing or downgrading the lock may unblock the others.
+                self.wake_lock_waiters();
+            }
+            // The requests are handled one by one, so the reply is deferred instead of blocking.
+            Ok(false) => self.lock_waiters.push(ino, lock, reply),
+            Err(ecode) => reply.error(ecode),
+        }
+    }
+
+    fn bmap(&mut self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
+        debug!(self.logger, "bmap");
+        self.metrics.io_operations_bmap.increment();
+
+        try_reply!(reply, self.ensure_device_present());
+
+        reply.error(libc::ENOSYS);
+    }
+}
+
+fn timespec_from(st: &std::time::SystemTime) -> Timespec {
+    if let Ok(dur_since_epoch) = st.duration_since(std::time::UNIX_EPOCH) {
+        Timespec::new(
+            dur_since_epoch.as_secs() as i64,
+            dur_since_epoch.subsec_nanos() as i32,
+        )
+    } else {
+        Timespec::new(0, 0)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_time_from(ts: &Timespec) -> std::time::SystemTime {
+    if 0 <= ts.sec {
+        std::time::UNIX_EPOCH + Duration::new(ts.sec as u64, ts.nsec as u32)
+    } else {
+        std::time::UNIX_EPOCH
+    }
+}
+
+fn fetch_fileattr(ino: u64, filepath: &Path) -> Result<FileAttr, io::Error> {
+    // Note that the symbolic links are not followed.
+    fileattr_from(ino, &fs::symlink_metadata(filepath)?)
+}
+
+fn fileattr_from(ino: u64, metadata: &fs::Metadata) -> Result<FileAttr, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    let kind = mode & libc::S_IFMT as u32;
+
+    // Note that `blksize` cannot be replied via `fuse`.
+    let mut attr: FileAttr = unsafe { mem::zeroed() };
+    attr.ino = ino;
+    attr.size = metadata.len();
+    attr.blocks = metadata.blocks();
+    attr.atime = Timespec::new(metadata.atime(), metadata.atime_nsec() as i32);
+    attr.mtime = Timespec::new(metadata.mtime(), metadata.mtime_nsec() as i32);
+    attr.ctime = Timespec::new(metadata.ctime(), metadata.ctime_nsec() as i32);
+    // The birth time is unavailable on some file systems.
+    attr.crtime = metadata
+        .created()
+        .map(|time| timespec_from(&time))
+        .unwrap_or(attr.ctime);
+    attr.kind = if kind == libc::S_IFREG as u32 {
+        FileType::RegularFile
+    } else if kind == libc::S_IFDIR as u32 {
+        FileType::Directory
+    } else if kind == libc::S_IFIFO as u32 {
+        FileType::NamedPipe
+    } else if kind == libc::S_IFCHR as u32 {
+        FileType::CharDevice
+    } else if kind == libc::S_IFBLK as u32 {
+        FileType::BlockDevice
+    } else if kind == libc::S_IFLNK as u32 {
+        FileType::Symlink
+    } else if kind == libc::S_IFSOCK as u32 {
+        FileType::Socket
+    } else {
+        return Err(io::Error::new(io::ErrorKind::Other, "unknown kind"));
+    };
+    // Including the set-user-ID, set-group-ID and sticky bits.
+    attr.perm = (mode & 0o7777) as u16;
+    attr.uid = metadata.uid();
+    attr.gid = metadata.gid();
+    attr.nlink = metadata.nlink() as u32;
+    attr.rdev = rdev_from(metadata.rdev());
+
+    Ok(attr)
+}
+
+/// Encodes the device number as the kernel decodes it from FUSE (see `new_decode_dev` of Linux).
+#[cfg(target_os = "linux")]
+fn rdev_from(dev: u64) -> u32 {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rdev_from(dev: u64) -> u32 {
+    dev as u32
+}
+
+/// Opens the file in the original directory with the flags of open(2).
+///
+/// Write-only files are opened with read access as well if possible
+/// because the overwritten contents are read to inject fsync faults.
+fn open_file(path: &Path, flags: u32, mode: u32) -> Result<File, io::Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let flags = flags as c_int;
+
+    // The buffers are not aligned for direct I/O.
+    #[cfg(target_os = "linux")]
+    let flags = flags & !libc::O_DIRECT;
+
+    let mut options = fs::OpenOptions::new();
+    // The flags except the access mode (e.g., O_APPEND, O_TRUNC, O_CREAT and O_EXCL) are passed as they are.
+    options.custom_flags(flags & !libc::O_ACCMODE).mode(mode);
+
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => {
+            let result = options.clone().read(true).write(true).open(path);
+            match result {
+                Err(ref error) if error.raw_os_error() == Some(libc::EACCES) => {
+                    options.write(true).open(path)
+                }
+                result => result,
+            }
+        }
+        libc::O_RDWR => options.read(true).write(true).open(path),
+        _ => options.read(true).open(path),
+    }
+}
+
+/// Replies the value read by `read`, or only its size if `size` is zero.
+///
+/// `ERANGE` is replied if the value is larger than `size` (see getxattr(2)).
+fn reply_xattr<F>(reply: ReplyXattr, size: u32, read: F)
+where
+    F: FnOnce(&mut [u8]) -> Result<usize, io::Error>,
+{
+    let mut buffer = vec![0; size as usize];
+    match read(&mut buffer) {
+        Ok(len) if size == 0 => reply.size(len as u32),
+        Ok(len) => reply.data(&buffer[..len]),
+        Err(error) => reply.error(errno_from(&error)),
+    }
+}
+
+/// Changes the timestamps of the file. The omitted ones are kept.
+///
+/// The file is not opened, so that the files the owner cannot read can be touched.
+/// The symbolic links are not followed.
+#[cfg(target_os = "linux")]
+fn set_file_times(
+    path: &Path,
+    file: Option<&File>,
+    atime: Option<Timespec>,
+    mtime: Option<Timespec>,
+) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let timespec = |time: Option<Timespec>| match time {
+        Some(time) => libc::timespec {
+            tv_sec: time.sec as libc::time_t,
+            tv_nsec: libc::c_long::from(time.nsec),
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+    let times = [timespec(atime), timespec(mtime)];
+
+    let result = match file {
+        Some(file) => unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) },
+        None => {
+            let path = cstring_from(path)?;
+            unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            }
+        }
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Changes the timestamps of the file. The omitted ones are kept.
+///
+/// The timestamps of symbolic links cannot be changed via `File`.
+#[cfg(not(target_os = "linux"))]
+fn set_file_times(
+    path: &Path,
+    file: Option<&File>,
+    atime: Option<Timespec>,
+    mtime: Option<Timespec>,
+) -> Result<(), io::Error> {
+    let mut times = fs::FileTimes::new();
+    if let Some(ref atime) = atime {
+        times = times.set_accessed(system_time_from(atime));
+    }
+    if let Some(ref mtime) = mtime {
+        times = times.set_modified(system_time_from(mtime));
+    }
+
+    match file {
+        Some(file) => file.set_times(times),
+        None if fs::symlink_metadata(path)?.file_type().is_symlink() => Ok(()),
+        None => File::open(path)?.set_times(times),
+    }
+}
+
+fn cstring_from(path: &Path) -> Result<CString, io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+fn statvfs(path: &Path) -> Result<libc::statvfs, io::Error> {
+    let path = cstring_from(path)?;
+    let mut st: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(st)
+    }
+}
+
+/// Converts the error into the error number replied to the kernel.
+fn errno_from(error: &io::Error) -> c_int {
+    match error.raw_os_error() {
+        Some(ecode) => ecode,
+        None if error.kind() == io::ErrorKind::NotFound => libc::ENOENT,
+        None => libc::EIO,
+    }
+}
+
+/// Replaces the prefix `from` of the path with `to`.
+/// Returns `None` if the path does not start with `from`.
+fn replace_path_prefix(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(from).ok()?;
+
+    if rest.as_os_str().is_empty() {
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(rest))
+    }
+}
+
+/// Returns the paths of the files in the directory merging the layers in the overlay mode.
+fn list_dir(overlay: Option<&Overlay>, dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    match overlay {
+        Some(overlay) => overlay.read_dir(dir),
+        None => fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect(),
+    }
+}
+
+/// `request_bps` means request Byte per seconds (not bit).
+/// `count_byte` is the number of read/written bytes.
+/// `elapsed_ms` is the elapsed time in milliseconds to read/write data.
+fn compute_sleep_duration_to_adjust_speed(
+    request_bps: usize,
+    count_byte: usize,
+    elapsed_ms: u64,
+) -> Duration {
+    if request_bps == 0 {
+        panic!("The given request bps is zero.");
+    }
+
+    let expect_sec = count_byte as f64 / request_bps as f64;
+    let expect_ms = (expect_sec * 1000.0).round() as u64;
+    let wait_ms = expect_ms.saturating_sub(elapsed_ms);
+
+    Duration::from_millis(wait_ms as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Creates the original directory for the test (`{root}/original`) and returns the root.
+    fn test_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("mizumochi_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("original")).unwrap();
+        root
+    }
+
+    /// Returns the file system serving the original directory without mounting it.
+    fn new_fs(root: &Path, name: &str) -> Mizumochi {
+        mount_fs(root, name, None)
+    }
+
+    /// Returns the file system in the overlay mode whose upper directory is `{root}/upper`.
+    fn new_overlay_fs(root: &Path, name: &str) -> Mizumochi {
+        mount_fs(root, name, Some(root.join("upper")))
+    }
+
+    fn mount_fs(root: &Path, name: &str, upper_dir: Option<PathBuf>) -> Mizumochi {
+        let mut mizumochi = Mizumochi::new(
+            Logger::root(slog::Discard, o!()),
+            name,
+            root.join("original"),
+            root.join("mnt"),
+            upper_dir,
+            Arc::new(AtomicImmut::new(Config::default())),
+            Shutdown::new(),
+        );
+        mizumochi.init().unwrap();
+        mizumochi
+    }
+
+    fn current_caller() -> Caller {
+        Caller {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: std::process::id(),
+            groups: std::cell::OnceCell::from(Vec::new()),
+        }
+    }
+
+    fn children(mizumochi: &Mizumochi, dir: Inode) -> Vec<(Inode, PathBuf)> {
+        match mizumochi.file_map.get(&dir) {
+            Some(LocalFile::Directory(_, Some(files))) => files.clone(),
+            _ => panic!("{} is not fetched", dir),
+        }
+    }
+
+    fn ino_of(mizumochi: &mut Mizumochi, parent: Inode, name: &str) -> Inode {
+        Mizumochi::lookup(mizumochi, parent, OsStr::new(name))
+            .unwrap()
+            .ino
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let root = test_dir("passthrough");
+        let original = root.join("original");
+        fs::create_dir(original.join("src")).unwrap();
+        fs::write(original.join("src/a"), b"a").unwrap();
+        fs::write(original.join("src/b"), b"b").unwrap();
+        let mut mizumochi = new_fs(&root, "passthrough");
+
+        let src = ino_of(&mut mizumochi, ROOT_DIR_INO, "src");
+        let a = ino_of(&mut mizumochi, src, "a");
+        let b = ino_of(&mut mizumochi, src, "b");
+        let dst = mizumochi
+            .mkdir(ROOT_DIR_INO, OsStr::new("dst"), 0o755)
+            .unwrap()
+            .ino;
+        assert!(original.join("dst").is_dir());
+
+        // Rename across the directories.
+        mizumochi
+            .rename(src, OsStr::new("a"), dst, OsStr::new("a"))
+            .unwrap();
+        assert!(!original.join("src/a").exists());
+        assert_eq!(b"a".to_vec(), fs::read(original.join("dst/a")).unwrap());
+        assert_eq!(a, ino_of(&mut mizumochi, dst, "a"));
+        assert_eq!(&original.join("dst/a"), mizumochi.file_map[&a].path());
+        assert!(Mizumochi::lookup(&mut mizumochi, src, OsStr::new("a")).is_err());
+
+        // Rename over the existing file.
+        mizumochi
+            .rename(src, OsStr::new("b"), dst, OsStr::new("a"))
+            .unwrap();
+        assert_eq!(b"b".to_vec(), fs::read(original.join("dst/a")).unwrap());
+        assert_eq!(vec![(b, PathBuf::from("a"))], children(&mizumochi, dst));
+        assert!(children(&mizumochi, src).is_empty());
+        assert!(!mizumochi.file_map.contains_key(&a));
+
+        // Rename the directory with the files under it.
+        let sub = mizumochi.mkdir(dst, OsStr::new("sub"), 0o755).unwrap().ino;
+        mizumochi
+            .rename(ROOT_DIR_INO, OsStr::new("dst"), src, OsStr::new("moved"))
+            .unwrap();
+        let moved = original.join("src/moved");
+        assert_eq!(&moved.join("a"), mizumochi.file_map[&b].path());
+        assert_eq!(&moved.join("sub"), mizumochi.file_map[&sub].path());
+        assert_eq!(b, ino_of(&mut mizumochi, dst, "a"));
+
+        // The directory must be empty to remove it.
+        let error = mizumochi.rmdir(src, OsStr::new("moved")).unwrap_err();
+        assert_eq!(Some(libc::ENOTEMPTY), error.raw_os_error());
+        mizumochi.unlink(dst, OsStr::new("a")).unwrap();
+        mizumochi.rmdir(dst, OsStr::new("sub")).unwrap();
+        mizumochi.rmdir(src, OsStr::new("moved")).unwrap();
+        mizumochi.rmdir(ROOT_DIR_INO, OsStr::new("src")).unwrap();
+        assert_eq!(0, fs::read_dir(&original).unwrap().count());
+        assert!(children(&mizumochi, ROOT_DIR_INO).is_empty());
+        assert_eq!(1, mizumochi.file_map.len());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_links() {
+        let root = test_dir("links");
+        fs::write(root.join("original/a"), b"abc").unwrap();
+        let mut mizumochi = new_fs(&root, "links");
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+
+        // The symbolic link is read as it is created.
+        let attr = mizumochi
+            .symlink(ROOT_DIR_INO, OsStr::new("s"), Path::new("dir/../a"))
+            .unwrap();
+        assert_eq!(FileType::Symlink, attr.kind);
+        assert_eq!(
+            PathBuf::from("dir/../a"),
+            mizumochi.readlink(attr.ino).unwrap()
+        );
+        let lookup = Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("s")).unwrap();
+        assert_eq!(attr.ino, lookup.ino);
+        assert_eq!(
+            Some(libc::EINVAL),
+            mizumochi.readlink(a).unwrap_err().raw_os_error()
+        );
+
+        // The hard link shares the inode.
+        let attr = mizumochi.link(a, ROOT_DIR_INO, OsStr::new("b")).unwrap();
+        assert_eq!(a, attr.ino);
+        assert_eq!(2, attr.nlink);
+        for name in &["a", "b"] {
+            let attr = Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new(name)).unwrap();
+            assert_eq!((a, 2), (attr.ino, attr.nlink));
+        }
+
+        // The file stays readable via the other link.
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new("a")).unwrap();
+        assert!(Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("a")).is_err());
+        let attr = Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("b")).unwrap();
+        assert_eq!((a, 1), (attr.ino, attr.nlink));
+        let fh = mizumochi
+            .open(&current_caller(), a, libc::O_RDONLY as u32)
+            .unwrap();
+        let mut buffer = [0; 3];
+        assert_eq!(Ok(3), mizumochi.read(fh, &mut buffer, 0, 3));
+        assert_eq!(b"abc", &buffer);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_rename_to_hard_link() {
+        let root = test_dir("rename_to_hard_link");
+        fs::write(root.join("original/a"), b"abc").unwrap();
+        let mut mizumochi = new_fs(&root, "rename_to_hard_link");
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+        mizumochi.link(a, ROOT_DIR_INO, OsStr::new("b")).unwrap();
+
+        // Both names are kept as rename(2) does nothing.
+        mizumochi
+            .rename(ROOT_DIR_INO, OsStr::new("a"), ROOT_DIR_INO, OsStr::new("b"))
+            .unwrap();
+        mizumochi
+            .rename(ROOT_DIR_INO, OsStr::new("a"), ROOT_DIR_INO, OsStr::new("a"))
+            .unwrap();
+        assert_eq!(b"abc".to_vec(), fs::read(root.join("original/a")).unwrap());
+        assert_eq!(b"abc".to_vec(), fs::read(root.join("original/b")).unwrap());
+        assert_eq!(
+            vec![(a, PathBuf::from("a")), (a, PathBuf::from("b"))],
+            children(&mizumochi, ROOT_DIR_INO)
+        );
+        assert_eq!(a, ino_of(&mut mizumochi, ROOT_DIR_INO, "b"));
+        assert_eq!(2, mizumochi.fetch_attr(a).unwrap().nlink);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_unlink_open_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_dir("unlink_open");
+        fs::write(root.join("original/tmp"), b"abc").unwrap();
+        let mut mizumochi = new_fs(&root, "unlink_open");
+
+        let ino = ino_of(&mut mizumochi, ROOT_DIR_INO, "tmp");
+        let fh = mizumochi
+            .open(&current_caller(), ino, libc::O_RDWR as u32)
+            .unwrap();
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new("tmp")).unwrap();
+        assert!(Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("tmp")).is_err());
+
+        // The removed file can be used until it is closed.
+        assert_eq!(3, mizumochi.fetch_attr(ino).unwrap().size);
+        let attr = mizumochi
+            .setattr(ino, None, Some(0o600), None, None, Some(1), None, None)
+            .unwrap();
+        assert_eq!((0o600, 1), (attr.perm, attr.size));
+        let file = &mizumochi.fh_map[&fh].file;
+        assert_eq!(0o600, file.metadata().unwrap().permissions().mode() & 0o777);
+
+        // A new file of the same name is another file.
+        fs::write(root.join("original/tmp"), b"new").unwrap();
+        assert_eq!(1, mizumochi.fetch_attr(ino).unwrap().size);
+
+        assert!(mizumochi.close(fh).is_some());
+        assert!(mizumochi.fetch_attr(ino).is_err());
+        assert!(!mizumochi.file_map.contains_key(&ino));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_readdir() {
+        let root = test_dir("readdir");
+        for name in &["a", "b", "c", "d"] {
+            fs::write(root.join("original").join(name), name).unwrap();
+        }
+        let mut mizumochi = new_fs(&root, "readdir");
+
+        // Lists the entries from the offset until the given number of entries are added.
+        let read = |mizumochi: &mut Mizumochi, fh, offset, limit| {
+            let mut entries = Vec::new();
+            Mizumochi::readdir(mizumochi, ROOT_DIR_INO, fh, offset, |_, offset, _, name| {
+                if entries.len() == limit {
+                    return true;
+                }
+                entries.push((offset, name.to_string_lossy().into_owned()));
+                false
+            })
+            .unwrap();
+            entries
+        };
+        let names = |entries: &[(i64, String)]| {
+            let mut names = entries
+                .iter()
+                .map(|(_, name)| name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let fh = Mizumochi::opendir(&mut mizumochi, ROOT_DIR_INO).unwrap();
+        let mut entries = read(&mut mizumochi, fh, 0, 4);
+        assert_eq!(4, entries.len());
+
+        // The listing resumes without skipping or repeating the entries
+        // even if the entries are added or removed in between.
+        let (offset, listed) = entries[3].clone();
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new(&listed)).unwrap();
+        let unlisted = ["a", "b", "c", "d"]
+            .iter()
+            .find(|name| !names(&entries).contains(&name.to_string()))
+            .unwrap();
+        mizumochi
+            .unlink(ROOT_DIR_INO, OsStr::new(unlisted))
+            .unwrap();
+        mizumochi
+            .mkdir(ROOT_DIR_INO, OsStr::new("e"), 0o755)
+            .unwrap();
+        entries.extend(read(&mut mizumochi, fh, offset, 10));
+        assert_eq!(vec![".", "..", "a", "b", "c", "d"], names(&entries));
+        assert_eq!(
+            (1..=6).collect::<Vec<i64>>(),
+            entries
+                .iter()
+                .map(|(offset, _)| *offset)
+                .collect::<Vec<_>>()
+        );
+        assert!(read(&mut mizumochi, fh, 6, 10).is_empty());
+
+        // Listing from the beginning reflects the changes.
+        let entries = read(&mut mizumochi, fh, 0, 10);
+        let mut expected = vec![".", "..", "a", "b", "c", "d", "e"];
+        expected.retain(|name| *name != listed && name != unlisted);
+        assert_eq!(expected, names(&entries));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_revalidate_dir() {
+        let root = test_dir("revalidate_dir");
+        let original = root.join("original");
+        fs::write(original.join("a"), b"a").unwrap();
+        let mut mizumochi = new_fs(&root, "revalidate_dir");
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+
+        // The changes by mizumochi itself do not make the directory listed again.
+        mizumochi
+            .mkdir(ROOT_DIR_INO, OsStr::new("dst"), 0o755)
+            .unwrap();
+        assert_eq!(
+            fs::metadata(&original).unwrap().modified().unwrap(),
+            mizumochi.dir_checked[&ROOT_DIR_INO].1
+        );
+
+        // The changes by the others are found once the interval passes.
+        fs::write(original.join("b"), b"b").unwrap();
+        fs::remove_file(original.join("a")).unwrap();
+        mizumochi.dir_checked.get_mut(&ROOT_DIR_INO).unwrap().0 -= REVALIDATION_INTERVAL;
+        mizumochi.revalidate_dir(ROOT_DIR_INO, &original).unwrap();
+
+        let mut names = children(&mizumochi, ROOT_DIR_INO)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec![PathBuf::from("b"), PathBuf::from("dst")], names);
+        assert!(!mizumochi.file_map.contains_key(&a));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_assign_ino() {
+        let root = test_dir("assign_ino");
+        let mut mizumochi = new_fs(&root, "assign_ino");
+        let dev = mizumochi.root_dev;
+
+        // The original inode number is used on the same device.
+        assert_eq!(100, mizumochi.assign_ino_for_key((dev, 100)));
+        assert_eq!(100, mizumochi.assign_ino_for_key((dev, 100)));
+
+        // The other inode numbers are allocated from `FALLBACK_INO_BASE`.
+        assert_eq!(
+            FALLBACK_INO_BASE,
+            mizumochi.assign_ino_for_key((dev + 1, 100))
+        );
+        assert_eq!(
+            FALLBACK_INO_BASE + 1,
+            mizumochi.assign_ino_for_key((dev, ROOT_DIR_INO))
+        );
+        assert_eq!(
+            FALLBACK_INO_BASE + 2,
+            mizumochi.assign_ino_for_key((dev, FALLBACK_INO_BASE))
+        );
+
+        // The inode number used by another file is not reused.
+        mizumochi
+            .file_map
+            .insert(200, LocalFile::RegularFile(root.join("original/a")));
+        assert_eq!(
+            FALLBACK_INO_BASE + 3,
+            mizumochi.assign_ino_for_key((dev, 200))
+        );
+        mizumochi.file_map.insert(
+            FALLBACK_INO_BASE + 4,
+            LocalFile::RegularFile(root.join("original/b")),
+        );
+        assert_eq!(
+            FALLBACK_INO_BASE + 5,
+            mizumochi.assign_ino_for_key((dev + 1, 201))
+        );
+
+        // The keys are forgotten with the inode.
+        mizumochi.unmap_keys(100);
+        assert!(!mizumochi.ino_map.contains_key(&(dev, 100)));
+        assert_eq!(
+            Some(&FALLBACK_INO_BASE),
+            mizumochi.ino_map.get(&(dev + 1, 100))
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_forget() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = test_dir("forget");
+        let original = root.join("original");
+        fs::create_dir(original.join("dir")).unwrap();
+        fs::write(original.join("dir/a"), b"a").unwrap();
+        let mut mizumochi = new_fs(&root, "forget");
+
+        let dir = ino_of(&mut mizumochi, ROOT_DIR_INO, "dir");
+        let a = ino_of(&mut mizumochi, dir, "a");
+        mizumochi.count_lookup(dir);
+        mizumochi.count_lookup(a);
+        mizumochi.count_lookup(a);
+
+        // The files listed in the directories referred to by the kernel are kept for readdir.
+        mizumochi.forget(a, 1);
+        assert_eq!(Some(&1), mizumochi.lookup_counts.get(&a));
+        mizumochi.forget(a, 1);
+        assert!(mizumochi.file_map.contains_key(&a));
+        assert_eq!(vec![(a, PathBuf::from("a"))], children(&mizumochi, dir));
+
+        // Forgetting the directory drops its listing and the files in it.
+        mizumochi.forget(dir, 1);
+        assert!(mizumochi.lookup_counts.is_empty());
+        assert!(!mizumochi.file_map.contains_key(&a));
+        assert!(matches!(
+            mizumochi.file_map.get(&dir),
+            Some(LocalFile::Directory(_, None))
+        ));
+        assert!(!mizumochi.dir_checked.contains_key(&dir));
+
+        // The file which is no longer listed is forgotten once it is closed.
+        assert_eq!(a, ino_of(&mut mizumochi, dir, "a"));
+        let fh = mizumochi
+            .open(&current_caller(), a, libc::O_RDONLY as u32)
+            .unwrap();
+        mizumochi.count_lookup(a);
+        mizumochi.remove_child(dir, a, OsStr::new("a"));
+        mizumochi.forget(a, 1);
+        assert!(mizumochi.file_map.contains_key(&a));
+        mizumochi.fh_map.remove(&fh);
+        mizumochi.forget(a, 0);
+        assert!(!mizumochi.file_map.contains_key(&a));
+        assert!(!mizumochi.ino_keys.contains_key(&a));
+        let metadata = fs::metadata(original.join("dir/a")).unwrap();
+        assert!(!mizumochi
+            .ino_map
+            .contains_key(&(metadata.dev(), metadata.ino())));
+
+        // The forgotten file is recorded again if it is listed.
+        mizumochi.add_child(dir, a, OsStr::new("a"));
+        assert_eq!(a, ino_of(&mut mizumochi, dir, "a"));
+        assert_eq!(&original.join("dir/a"), mizumochi.file_map[&a].path());
+        assert_eq!(
+            Some(&a),
+            mizumochi.ino_map.get(&(metadata.dev(), metadata.ino()))
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_forget_tree() {
+        let root = test_dir("forget_tree");
+        let original = root.join("original");
+        fs::create_dir_all(original.join("a/b/c")).unwrap();
+        fs::write(original.join("a/b/c/d"), b"d").unwrap();
+        fs::write(original.join("a/b/e"), b"e").unwrap();
+        fs::write(original.join("a/f"), b"f").unwrap();
+        fs::write(original.join("g"), b"g").unwrap();
+        let mut mizumochi = new_fs(&root, "forget_tree");
+        let files = |mizumochi: &Mizumochi| {
+            let mut files = mizumochi.file_map.keys().cloned().collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let fetched = files(&mizumochi);
+        let keys = mizumochi.ino_keys.len();
+
+        // Walk the all files as the kernel does.
+        let mut looked_up = Vec::new();
+        let mut dirs = vec![ROOT_DIR_INO];
+        while let Some(dir) = dirs.pop() {
+            Mizumochi::fetched_dir_path(&mut mizumochi, dir).unwrap();
+            for (_, name) in children(&mizumochi, dir) {
+                let attr = Mizumochi::lookup(&mut mizumochi, dir, name.as_os_str()).unwrap();
+                mizumochi.count_lookup(attr.ino);
+                looked_up.push(attr.ino);
+                if attr.kind == FileType::Directory {
+                    dirs.push(attr.ino);
+                }
+            }
+        }
+        assert_eq!(7, looked_up.len());
+        assert_eq!(8, mizumochi.file_map.len());
+
+        // Only the root directory and its listing remain after the kernel forgets the others.
+        for ino in looked_up {
+            mizumochi.forget(ino, 1);
+        }
+        assert_eq!(fetched, files(&mizumochi));
+        assert_eq!(keys, mizumochi.ino_keys.len());
+        assert_eq!(vec![ROOT_DIR_INO], {
+            let mut dirs = mizumochi.dir_checked.keys().cloned().collect::<Vec<_>>();
+            dirs.sort();
+            dirs
+        });
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        use crate::config::Condition;
+
+        let root = test_dir("read_only");
+        fs::write(root.join("original/a"), b"abc").unwrap();
+        fs::create_dir(root.join("original/d")).unwrap();
+        let mut mizumochi = new_fs(&root, "read_only");
+        let caller = current_caller();
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+        let d = ino_of(&mut mizumochi, ROOT_DIR_INO, "d");
+        let writer = mizumochi.open(&caller, a, libc::O_RDWR as u32).unwrap();
+        let before = snapshot(&root.join("original"));
+
+        let mut config = (*mizumochi.config.load()).clone();
+        config.condition = Condition::Always(State::Unstable);
+        config.faults.read_only = true;
+        mizumochi.config.store(config);
+
+        let errno = |result: Result<(), io::Error>| result.err().and_then(|e| e.raw_os_error());
+        let name = OsStr::new("b");
+        let flags = libc::O_RDWR as u32;
+        let mutations = vec![
+            errno(
+                mizumochi
+                    .create(&caller, ROOT_DIR_INO, name, 0o644, flags)
+                    .map(|_| ()),
+            ),
+            errno(mizumochi.mkdir(ROOT_DIR_INO, name, 0o755).map(|_| ())),
+            errno(
+                mizumochi
+                    .mknod(ROOT_DIR_INO, name, libc::S_IFIFO | 0o644, 0)
+                    .map(|_| ()),
+            ),
+            errno(
+                mizumochi
+                    .symlink(ROOT_DIR_INO, name, Path::new("a"))
+                    .map(|_| ()),
+            ),
+            errno(mizumochi.link(a, ROOT_DIR_INO, name).map(|_| ())),
+            errno(mizumochi.unlink(ROOT_DIR_INO, OsStr::new("a"))),
+            errno(mizumochi.rmdir(ROOT_DIR_INO, OsStr::new("d"))),
+            errno(mizumochi.rename(ROOT_DIR_INO, OsStr::new("a"), d, name)),
+            errno(
+                mizumochi
+                    .setattr(a, None, Some(0o600), None, None, Some(0), None, None)
+                    .map(|_| ()),
+            ),
+            errno(
+                mizumochi
+                    .open(&caller, a, libc::O_WRONLY as u32)
+                    .map(|_| ()),
+            ),
+            errno(
+                mizumochi
+                    .open(&caller, a, (libc::O_RDONLY | libc::O_TRUNC) as u32)
+                    .map(|_| ()),
+            ),
+            errno(mizumochi.setxattr(a, OsStr::new("user.a"), b"a", 0)),
+            errno(mizumochi.removexattr(a, OsStr::new("user.a"))),
+            mizumochi.write(writer, b"d", 0).err(),
+            mizumochi.access(&caller, a, permission::W_OK).err(),
+        ];
+        for (i, errno) in mutations.into_iter().enumerate() {
+            assert_eq!(Some(libc::EROFS), errno, "mutation #{}", i);
+        }
+        assert_eq!(before, snapshot(&root.join("original")));
+
+        // The files can still be read.
+        assert!(Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("a")).is_ok());
+        assert_eq!(3, mizumochi.fetch_attr(a).unwrap().size);
+        assert_eq!(Ok(()), mizumochi.access(&caller, a, permission::R_OK));
+        let reader = mizumochi.open(&caller, a, libc::O_RDONLY as u32).unwrap();
+        let mut buffer = [0; 3];
+        assert_eq!(Ok(3), mizumochi.read(reader, &mut buffer, 0, 3));
+        assert_eq!(b"abc", &buffer);
+        assert_eq!(Ok(3), mizumochi.read(writer, &mut buffer, 0, 3));
+        assert_eq!(0, Mizumochi::statfs(&mut mizumochi).unwrap().bavail);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_disappear() {
+        use crate::config::Condition;
+
+        let root = test_dir("disappear");
+        fs::write(root.join("original/a"), b"abc").unwrap();
+        let mut mizumochi = new_fs(&root, "disappear");
+        let caller = current_caller();
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+        let fh = mizumochi.open(&caller, a, libc::O_RDWR as u32).unwrap();
+
+        let mut config = (*mizumochi.config.load()).clone();
+        config.condition = Condition::Always(State::Unstable);
+        config.faults = serde_json::from_str(r#"{"disappear": "NotConnected"}"#).unwrap();
+        mizumochi.config.store(config.clone());
+        assert_eq!(Err(libc::ENOTCONN), mizumochi.ensure_device_present());
+        assert_eq!(Err(libc::ENOTCONN), mizumochi.ensure_writable());
+        assert_eq!(
+            Err(libc::ENOTCONN),
+            mizumochi.access(&caller, a, permission::R_OK)
+        );
+        assert_eq!(Err(libc::ENOTCONN), mizumochi.write(fh, b"d", 0));
+
+        config.faults = serde_json::from_str(r#"{"disappear": "IoError"}"#).unwrap();
+        mizumochi.config.store(config.clone());
+        assert_eq!(Err(libc::EIO), mizumochi.ensure_device_present());
+
+        // The handle opened before the disappearance is stale after the device returns.
+        config.condition = Condition::Always(State::Stable);
+        mizumochi.config.store(config);
+        assert_eq!(Ok(()), mizumochi.ensure_device_present());
+        let mut buffer = [0; 3];
+        assert_eq!(Err(libc::ESTALE), mizumochi.read(fh, &mut buffer, 0, 3));
+        assert_eq!(Err(libc::ESTALE), mizumochi.write(fh, b"d", 0));
+        mizumochi.close(fh);
+
+        // The files can be opened again.
+        let fh = mizumochi.open(&caller, a, libc::O_RDWR as u32).unwrap();
+        assert_eq!(Ok(3), mizumochi.read(fh, &mut buffer, 0, 3));
+        assert_eq!(b"abc", &buffer);
+        assert_eq!(Ok(1), mizumochi.write(fh, b"d", 0));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_fault_timestamps() {
+        use crate::config::Condition;
+        use std::time::UNIX_EPOCH;
+
+        let root = test_dir("fault_timestamps");
+        let path = root.join("original/a");
+        fs::write(&path, b"a").unwrap();
+        let mut mizumochi = new_fs(&root, "fault_timestamps");
+        let a = ino_of(&mut mizumochi, ROOT_DIR_INO, "a");
+        let mtime = mizumochi.fetch_attr(a).unwrap().mtime;
+
+        // The timestamps are taken when the state gets unstable.
+        let mut config = (*mizumochi.config.load()).clone();
+        config.condition = Condition::Always(State::Unstable);
+        config.faults.timestamps = Some(TimestampFault::Stale);
+        mizumochi.config.store(config.clone());
+        mizumochi.change_state_if_necessary(Operation::Read);
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1000))
+            .unwrap();
+        let attr = mizumochi.fetch_attr(a).unwrap();
+        assert_eq!(1000, attr.mtime.sec);
+        assert_eq!(mtime, mizumochi.fault_timestamps(attr).mtime);
+
+        config.faults.timestamps = Some(TimestampFault::Skew(-10));
+        mizumochi.config.store(config.clone());
+        assert_eq!(990, mizumochi.fault_timestamps(attr).mtime.sec);
+
+        config.condition = Condition::Always(State::Stable);
+        mizumochi.config.store(config);
+        assert_eq!(attr.mtime, mizumochi.fault_timestamps(attr).mtime);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Returns the modes, the mtimes and the contents of the files under the directory.
+    fn snapshot(dir: &Path) -> Vec<(PathBuf, u32, SystemTime, Vec<u8>)> {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            let contents = if metadata.is_dir() {
+                files.extend(snapshot(&path));
+                Vec::new()
+            } else {
+                fs::read(&path).unwrap()
+            };
+            files.push((
+                path,
+                metadata.mode(),
+                metadata.modified().unwrap(),
+                contents,
+            ));
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_overlay() {
+        let root = test_dir("overlay_mode");
+        let original = root.join("original");
+        let upper = root.join("upper");
+        fs::create_dir_all(original.join("dir/sub")).unwrap();
+        fs::create_dir(original.join("empty")).unwrap();
+        fs::write(original.join("a"), b"a").unwrap();
+        fs::write(original.join("dir/b"), b"b").unwrap();
+        fs::write(original.join("dir/sub/c"), b"c").unwrap();
+        fs::write(original.join("t"), b"truncated").unwrap();
+        let before = snapshot(&original);
+        let mut mizumochi = new_overlay_fs(&root, "overlay_mode");
+
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new("a")).unwrap();
+        assert!(Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("a")).is_err());
+
+        let dir = ino_of(&mut mizumochi, ROOT_DIR_INO, "dir");
+        mizumochi
+            .rename(dir, OsStr::new("b"), ROOT_DIR_INO, OsStr::new("b"))
+            .unwrap();
+        assert_eq!(b"b".to_vec(), fs::read(upper.join("b")).unwrap());
+        assert!(Mizumochi::lookup(&mut mizumochi, dir, OsStr::new("b")).is_err());
+
+        // The directories in the lower layer cannot be renamed.
+        let error = mizumochi
+            .rename(
+                ROOT_DIR_INO,
+                OsStr::new("dir"),
+                ROOT_DIR_INO,
+                OsStr::new("x"),
+            )
+            .unwrap_err();
+        assert_eq!(Some(libc::EXDEV), error.raw_os_error());
+
+        // The directory recreated over the removed one hides it.
+        mizumochi.rmdir(ROOT_DIR_INO, OsStr::new("empty")).unwrap();
+        assert!(Mizumochi::lookup(&mut mizumochi, ROOT_DIR_INO, OsStr::new("empty")).is_err());
+        mizumochi
+            .mkdir(ROOT_DIR_INO, OsStr::new("empty"), 0o755)
+            .unwrap();
+        mizumochi
+            .rename(
+                ROOT_DIR_INO,
+                OsStr::new("empty"),
+                ROOT_DIR_INO,
+                OsStr::new("moved"),
+            )
+            .unwrap();
+        assert!(upper.join("moved").is_dir());
+
+        let sub = ino_of(&mut mizumochi, dir, "sub");
+        let c = ino_of(&mut mizumochi, sub, "c");
+        let attr = mizumochi
+            .setattr(c, None, Some(0o600), None, None, Some(0), None, None)
+            .unwrap();
+        assert_eq!((0o600, 0), (attr.perm, attr.size));
+        assert!(fs::read(upper.join("dir/sub/c")).unwrap().is_empty());
+
+        let t = ino_of(&mut mizumochi, ROOT_DIR_INO, "t");
+        let flags = (libc::O_WRONLY | libc::O_TRUNC) as u32;
+        let fh = mizumochi.open(&current_caller(), t, flags).unwrap();
+        mizumochi.close(fh);
+        assert!(fs::read(upper.join("t")).unwrap().is_empty());
+
+        // The usage counts the files shown on the mountpoint.
+        let used = compute_disk_usage(&original, &|dir| mizumochi.list_dir(dir)).unwrap();
+        assert_eq!(1, used);
+
+        assert_eq!(before, snapshot(&original));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_deny_access() {
+        use crate::config::Condition;
+
+        let root = test_dir("deny_access");
+        let original = root.join("original");
+        fs::create_dir_all(original.join("secret/dir")).unwrap();
+        fs::write(original.join("secret/a"), b"a").unwrap();
+        fs::write(original.join("secret/dir/b"), b"b").unwrap();
+        fs::write(original.join("public"), b"c").unwrap();
+        let mut mizumochi = new_overlay_fs(&root, "deny_access");
+        let caller = current_caller();
+        let secret = ino_of(&mut mizumochi, ROOT_DIR_INO, "secret");
+        let dir = ino_of(&mut mizumochi, secret, "dir");
+        let a = ino_of(&mut mizumochi, secret, "a");
+        let b = ino_of(&mut mizumochi, dir, "b");
+        let public = ino_of(&mut mizumochi, ROOT_DIR_INO, "public");
+
+        // Copy up the file, and create a file only in the upper directory.
+        let fh = mizumochi.open(&caller, a, libc::O_RDWR as u32).unwrap();
+        mizumochi.close(fh);
+        assert!(root.join("upper/secret/a").exists());
+        let (attr, fh) = mizumochi
+            .create(&caller, dir, OsStr::new("c"), 0o644, libc::O_RDWR as u32)
+            .unwrap();
+        mizumochi.close(fh);
+
+        let mut config = (*mizumochi.config.load()).clone();
+        config.condition = Condition::Always(State::Unstable);
+        config.faults.deny_access = vec![PathBuf::from("/secret/dir"), PathBuf::from("secret/a")];
+        mizumochi.config.store(config);
+        for ino in &[a, b, dir, attr.ino] {
+            assert_eq!(
+                Err(libc::EACCES),
+                mizumochi.access(&caller, *ino, permission::R_OK),
+                "ino: {}",
+                ino
+            );
+        }
+        assert_eq!(Ok(()), mizumochi.access(&caller, secret, permission::R_OK));
+        assert_eq!(Ok(()), mizumochi.access(&caller, public, permission::R_OK));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_capacity() {
+        use crate::config::{Capacity, NoSpaceError};
+
+        let root = test_dir("capacity");
+        fs::write(root.join("original/a"), b"abcd").unwrap();
+        let mut mizumochi = new_fs(&root, "capacity");
+        let mut config = (*mizumochi.config.load()).clone();
+        config.capacity = Some(Capacity {
+            bytes: 10,
+            error: NoSpaceError::NoSpace,
+        });
+        mizumochi.config.store(config.clone());
+
+        // The usage is computed in the background.
+        let mut free = None;
+        for _ in 0..1000 {
+            free = mizumochi.free_bytes().unwrap();
+            if free.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(Some(6), free);
+
+        let flags = libc::O_RDWR as u32;
+        let (_, fh) = mizumochi
+            .create(
+                &current_caller(),
+                ROOT_DIR_INO,
+                OsStr::new("b"),
+                0o644,
+                flags,
+            )
+            .unwrap();
+
+        // The write filling the capacity exactly succeeds, and the next byte does not fit.
+        assert_eq!(Ok(4), mizumochi.write(fh, b"efgh", 0));
+        assert_eq!(Ok(2), mizumochi.write(fh, b"ij", 2));
+        assert_eq!(Ok(2), mizumochi.write(fh, b"kl", 4));
+        assert_eq!(Some(0), mizumochi.free_bytes().unwrap());
+        assert_eq!(Err(libc::ENOSPC), mizumochi.write(fh, b"m", 6));
+        assert_eq!(
+            b"efijkl".to_vec(),
+            fs::read(root.join("original/b")).unwrap()
+        );
+
+        // Overwriting does not need more space unless it extends the file.
+        assert_eq!(Ok(2), mizumochi.write(fh, b"no", 0));
+        assert_eq!(Err(libc::ENOSPC), mizumochi.write(fh, b"pq", 5));
+
+        config.capacity = Some(Capacity {
+            bytes: 10,
+            error: NoSpaceError::QuotaExceeded,
+        });
+        mizumochi.config.store(config);
+        assert_eq!(Err(libc::EDQUOT), mizumochi.write(fh, b"m", 6));
+
+        // Removing the files frees the space.
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new("a")).unwrap();
+        assert_eq!(Some(4), mizumochi.free_bytes().unwrap());
+        assert_eq!(Ok(1), mizumochi.write(fh, b"m", 6));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_max_files() {
+        let root = test_dir("max_files");
+        let mut mizumochi = new_fs(&root, "max_files");
+        let mut config = (*mizumochi.config.load()).clone();
+        // The root directory counts as a file.
+        config.max_files = Some(3);
+        mizumochi.config.store(config);
+
+        let caller = current_caller();
+        let flags = libc::O_RDWR as u32;
+        for name in &["a", "b"] {
+            let (_, fh) = mizumochi
+                .create(&caller, ROOT_DIR_INO, OsStr::new(name), 0o644, flags)
+                .unwrap();
+            mizumochi.close(fh);
+        }
+
+        let st = Mizumochi::statfs(&mut mizumochi).unwrap();
+        assert_eq!((3, 0), (st.files, st.ffree));
+
+        // No more files can be created.
+        let errno = |result: Result<(), io::Error>| result.err().and_then(|e| e.raw_os_error());
+        assert_eq!(
+            Some(libc::ENOSPC),
+            errno(
+                mizumochi
+                    .create(&caller, ROOT_DIR_INO, OsStr::new("c"), 0o644, flags)
+                    .map(|_| ())
+            )
+        );
+        assert_eq!(
+            Some(libc::ENOSPC),
+            errno(
+                mizumochi
+                    .mkdir(ROOT_DIR_INO, OsStr::new("c"), 0o755)
+                    .map(|_| ())
+            )
+        );
+        assert!(!root.join("original/c").exists());
+
+        // Removing a file frees a slot.
+        mizumochi.unlink(ROOT_DIR_INO, OsStr::new("a")).unwrap();
+        let st = Mizumochi::statfs(&mut mizumochi).unwrap();
+        assert_eq!((3, 1), (st.files, st.ffree));
+        mizumochi
+            .mkdir(ROOT_DIR_INO, OsStr::new("c"), 0o755)
+            .unwrap();
+        let st = Mizumochi::statfs(&mut mizumochi).unwrap();
+        assert_eq!((3, 0), (st.files, st.ffree));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_statfs() {
+        use crate::config::{Capacity, Condition, NoSpaceError};
+
+        let root = test_dir("statfs");
+        fs::write(root.join("original/a"), vec![0; 4096]).unwrap();
+        let mut mizumochi = new_fs(&root, "statfs");
+        let block_size = u64::from(Mizumochi::statfs(&mut mizumochi).unwrap().frsize);
+        let counts = |mizumochi: &mut Mizumochi| {
+            let st = Mizumochi::statfs(mizumochi).unwrap();
+            (st.blocks, st.bfree, st.bavail, st.files, st.ffree)
+        };
+
+        let mut config = (*mizumochi.config.load()).clone();
+        config.statfs.total_bytes = Some(100 * block_size);
+        config.statfs.free_bytes = Some(40 * block_size + 1);
+        config.statfs.total_files = Some(1000);
+        config.statfs.free_files = Some(300);
+        mizumochi.config.store(config.clone());
+        assert_eq!((100, 40, 40, 1000, 300), counts(&mut mizumochi));
+
+        // The free numbers are scaled and rounded down.
+        config.statfs.free_ratio = Some(0.25);
+        mizumochi.config.store(config.clone());
+        assert_eq!((100, 10, 10, 1000, 75), counts(&mut mizumochi));
+        config.statfs.free_ratio = Some(-1.0);
+        mizumochi.config.store(config.clone());
+        assert_eq!((100, 0, 0, 1000, 0), counts(&mut mizumochi));
+
+        // The free numbers never exceed the totals.
+        config.statfs.free_ratio = Some(10.0);
+        mizumochi.config.store(config.clone());
+        assert_eq!((100, 100, 100, 1000, 1000), counts(&mut mizumochi));
+
+        // The virtual capacity and the maximum number of files take precedence.
+        config.capacity = Some(Capacity {
+            bytes: 10 * block_size,
+            error: NoSpaceError::NoSpace,
+        });
+        config.max_files = Some(5);
+        mizumochi.config.store(config.clone());
+        for _ in 0..1000 {
+            if mizumochi.free_bytes().unwrap().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let free_blocks = (10 * block_size - 4096) / block_size;
+        assert_eq!(
+            (
+                10,
+                free_blocks,
+                free_blocks,
+                5,
+                5 - mizumochi.file_map.len() as u64
+            ),
+            counts(&mut mizumochi)
+        );
+
+        // No free space is reported while the mountpoint is read-only.
+        config.condition = Condition::Always(State::Unstable);
+        config.faults.read_only = true;
+        mizumochi.config.store(config);
+        assert_eq!((10, free_blocks, 0, 5, 0), counts(&mut mizumochi));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        use crate::config::Condition;
+
+        let root = test_dir("lock");
+        fs::write(root.join("original/tmp"), b"abc").unwrap();
+        let mut mizumochi = new_fs(&root, "lock");
+        let ino = ino_of(&mut mizumochi, ROOT_DIR_INO, "tmp");
+
+        let lock = |owner, typ| Lock {
+            owner,
+            start: 0,
+            end: 9,
+            typ,
+            pid: 100 + owner as u32,
+        };
+        assert_eq!(
+            Ok(true),
+            mizumochi.setlk(ino, lock(1, libc::F_WRLCK), false)
+        );
+        assert_eq!(
+            Some(lock(1, libc::F_WRLCK)),
+            Mizumochi::getlk(&mizumochi, ino, &lock(2, libc::F_RDLCK))
+        );
+        assert_eq!(
+            Err(libc::EAGAIN),
+            mizumochi.setlk(ino, lock(2, libc::F_RDLCK), false)
+        );
+        // Waiting for the lock is deferred.
+        assert_eq!(
+            Ok(false),
+            mizumochi.setlk(ino, lock(2, libc::F_RDLCK), true)
+        );
+
+        // The lock is released when the owner closes the file.
+        mizumochi.locks.release_owner(ino, 1);
+        assert_eq!(
+            Ok(true),
+            mizumochi.setlk(ino, lock(2, libc::F_RDLCK), false)
+        );
+
+        // The fault denies acquiring the locks but not releasing them.
+        let mut config = (*mizumochi.config.load()).clone();
+        config.condition = Condition::Always(State::Unstable);
+        config.faults.lock = Some(LockFault::Deny);
+        mizumochi.config.store(config);
+        assert_eq!(
+            Err(libc::EAGAIN),
+            mizumochi.setlk(ino, lock(3, libc::F_RDLCK), false)
+        );
+        assert_eq!(
+            Ok(true),
+            mizumochi.setlk(ino, lock(2, libc::F_UNLCK), false)
+        );
+        assert_eq!(
+            None,
+            Mizumochi::getlk(&mizumochi, ino, &lock(3, libc::F_WRLCK))
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_replace_path_prefix() {
+        let from = Path::new("/tmp/a");
+        let to = Path::new("/tmp/b/c");
+
+        assert_eq!(
+            Some(PathBuf::from("/tmp/b/c")),
+            replace_path_prefix(Path::new("/tmp/a"), from, to)
+        );
+        assert_eq!(
+            Some(PathBuf::from("/tmp/b/c/d/e")),
+            replace_path_prefix(Path::new("/tmp/a/d/e"), from, to)
+        );
+        assert_eq!(None, replace_path_prefix(Path::new("/tmp/ab"), from, to));
+        assert_eq!(None, replace_path_prefix(Path::new("/tmp"), from, to));
+    }
+
+    #[test]
+    fn test_open_file() {
+        let path = std::env::temp_dir().join(format!("mizumochi_open_{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let mut f = open_file(&path, flags, 0).unwrap();
+        f.write_all(b"def").unwrap();
+        assert_eq!(b"abcdef".to_vec(), fs::read(&path).unwrap());
+
+        let mut f = open_file(&path, libc::O_RDONLY as u32, 0).unwrap();
+        assert!(f.write_all(b"ghi").is_err());
+
+        let flags = (libc::O_RDWR | libc::O_TRUNC) as u32;
+        open_file(&path, flags, 0).unwrap();
+        assert_eq!(0, fs::metadata(&path).unwrap().len());
+
+        let flags = (libc::O_RDWR | libc::O_CREAT | libc::O_EXCL) as u32;
+        let error = open_file(&path, flags, 0o644).unwrap_err();
+        assert_eq!(Some(libc::EEXIST), error.raw_os_error());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_fileattr() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let path = std::env::temp_dir().join(format!("mizumochi_attr_{}", std::process::id()));
+        fs::write(&path, vec![1; 8192]).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4750)).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        let attr = fetch_fileattr(2, &path).unwrap();
+        assert_eq!(0o4750, attr.perm);
+        assert_eq!(metadata.blocks(), attr.blocks);
+        assert_eq!(
+            Timespec::new(metadata.ctime(), metadata.ctime_nsec() as i32),
+            attr.ctime
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_set_file_times() {
+        use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+        let dir = test_dir("times").join("original");
+        let (path, link) = (dir.join("file"), dir.join("link"));
+        fs::write(&path, b"abc").unwrap();
+        // The owner cannot read the file.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o200)).unwrap();
+        symlink(&path, &link).unwrap();
+        let atime = fs::metadata(&path).unwrap().atime();
+
+        set_file_times(&path, None, None, Some(Timespec::new(1000, 500))).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!((1000, 500), (metadata.mtime(), metadata.mtime_nsec()));
+        assert_eq!(atime, metadata.atime());
+
+        #[cfg(target_os = "linux")]
+        {
+            // The target of the symbolic link is not changed.
+            set_file_times(&link, None, Some(Timespec::new(2000, 0)), None).unwrap();
+            assert_eq!(2000, fs::symlink_metadata(&link).unwrap().atime());
+            assert_eq!(atime, fs::metadata(&path).unwrap().atime());
+        }
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        set_file_times(&path, Some(&file), None, Some(Timespec::new(3000, 0))).unwrap();
+        assert_eq!(3000, fs::metadata(&path).unwrap().mtime());
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_rdev_from() {
+        // /dev/sda1
+        assert_eq!(0x801, rdev_from(0x801));
+        // The minor number larger than 255.
+        assert_eq!(0x10082c, rdev_from(0x10082c));
+    }
 
     #[test]
     fn test_compute_sleep_duration_to_adjust_speed() {
@@ -0,0 +1,383 @@
+//! The mounts managed by a mizumochi process.
+use crate::config::{Config, MountSpec};
+use crate::mizumochi::Mizumochi;
+use crate::overlay::Overlay;
+use crate::shutdown::{self, Shutdown};
+use atomic_immut::AtomicImmut;
+use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// The name of the mount given on the command line.
+pub const DEFAULT_MOUNT: &str = "default";
+
+struct Entry {
+    // The config of the spec is not used. See `config`.
+    spec: MountSpec,
+    config: Arc<AtomicImmut<Config>>,
+    shutdown: Shutdown,
+    removing: bool,
+    resetting: bool,
+    // The mount is mounted outside the lock (see `add`).
+    mounting: bool,
+}
+
+/// `Mounts` is shared by the HTTP server and the threads serving the mounts.
+#[derive(Clone)]
+pub struct Mounts {
+    logger: Logger,
+    force_unmount_stale: bool,
+    inner: Arc<(Mutex<HashMap<String, Entry>>, Condvar)>,
+}
+
+impl Mounts {
+    pub fn new(logger: Logger, force_unmount_stale: bool) -> Mounts {
+        Mounts {
+            logger,
+            force_unmount_stale,
+            inner: Arc::new((Mutex::new(HashMap::new()), Condvar::new())),
+        }
+    }
+
+    /// Mounts the original directory and serves it in a new thread.
+    pub fn add(&self, name: &str, spec: MountSpec) -> Result<(), io::Error> {
+        validate_name(name)?;
+        let args = spec
+            .options
+            .to_args()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if !spec.original_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not directory", spec.original_dir),
+            ));
+        }
+        if let Some(ref upper_dir) = spec.upper_dir {
+            Overlay::open(&spec.original_dir, upper_dir, &spec.mountpoint)?;
+        }
+
+        let config = Arc::new(AtomicImmut::new(spec.config.clone().unwrap_or_default()));
+        let shutdown = Shutdown::new();
+
+        // Reserve the name and the mountpoint, and mount it without blocking the other requests.
+        {
+            let mut entries = self.inner.0.lock().unwrap();
+            if entries.contains_key(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is already mounted", name),
+                ));
+            }
+            if entries
+                .values()
+                .any(|entry| entry.spec.mountpoint == spec.mountpoint)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} is already used", spec.mountpoint),
+                ));
+            }
+            entries.insert(
+                name.to_string(),
+                Entry {
+                    spec: MountSpec {
+                        config: None,
+                        ..spec.clone()
+                    },
+                    config: Arc::clone(&config),
+                    shutdown: shutdown.clone(),
+                    removing: false,
+                    resetting: false,
+                    mounting: true,
+                },
+            );
+        }
+
+        let logger = self.logger.new(o!("mount" => name.to_string()));
+        info!(logger, "original directory: {:?}", spec.original_dir);
+        info!(logger, "mountpoint: {:?}", spec.mountpoint);
+        info!(logger, "config: {}", config.load());
+        info!(logger, "mount options: {:?}", spec.options);
+
+        let mounted = self
+            .ensure_not_stale(&spec.mountpoint)
+            .and_then(|()| self.spawn(name, &spec, config, shutdown, &args));
+
+        let mut entries = self.inner.0.lock().unwrap();
+        if let Err(error) = mounted {
+            entries.remove(name);
+            self.inner.1.notify_all();
+            return Err(error);
+        }
+        // The entry is removed if the session has ended already.
+        if let Some(entry) = entries.get_mut(name) {
+            entry.mounting = false;
+            self.unmount_if_removed(name, entry);
+        }
+        Ok(())
+    }
+
+    /// Unmounts the mount removed while it is mounted outside the lock.
+    fn unmount_if_removed(&self, name: &str, entry: &mut Entry) {
+        if entry.removing {
+            entry.removing = false;
+            if let Err(error) = unmount(entry) {
+                error!(self.logger, "Cannot unmount {}: {}", name, error);
+            }
+        }
+    }
+
+    /// Mounts the file system and serves it in a new thread.
+    fn spawn(
+        &self,
+        name: &str,
+        spec: &MountSpec,
+        config: Arc<AtomicImmut<Config>>,
+        shutdown: Shutdown,
+        args: &[OsString],
+    ) -> Result<(), io::Error> {
+        let logger = self.logger.new(o!("mount" => name.to_string()));
+        let mut session = Mizumochi::new(
+            logger.clone(),
+            name,
+            spec.original_dir.clone(),
+            spec.mountpoint.clone(),
+            spec.upper_dir.clone(),
+            config,
+            shutdown,
+        )
+        .mount(args)?;
+
+        let mounts = self.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            if let Err(error) = session.run() {
+                error!(logger, "{}", error);
+            }
+            // The files still open are synced on dropping the session.
+            drop(session);
+            info!(logger, "unmounted");
+            mounts.finish(&name);
+        });
+        Ok(())
+    }
+
+    /// Discards the changes in the upper directory of the mount in the overlay mode.
+    /// The mount is mounted again, so it fails while the files on it are in use.
+    ///
+    /// It returns after unmounting, and the mount is mounted again
+    /// by the thread of the session when it ends (see `finish`).
+    pub fn reset(&self, name: &str) -> Result<(), io::Error> {
+        let mut entries = self.inner.0.lock().unwrap();
+        let entry = match entries.get_mut(name) {
+            Some(entry) if !entry.removing && !entry.resetting && !entry.mounting => entry,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not mounted", name),
+                ))
+            }
+        };
+        if entry.spec.upper_dir.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not in the overlay mode", name),
+            ));
+        }
+
+        shutdown::unmount_unused(&entry.spec.mountpoint).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("Cannot unmount {}: {}", name, error),
+            )
+        })?;
+        entry.shutdown.request();
+        entry.resetting = true;
+        Ok(())
+    }
+
+    /// Clears the upper directory of the resetting mount and mounts it again.
+    /// The mount is removed if it fails or the mount is removed in the meantime.
+    fn remount(&self, name: &str, spec: MountSpec, config: Arc<AtomicImmut<Config>>) {
+        let upper_dir = spec.upper_dir.clone().unwrap_or_default();
+        let cleared = Overlay::new(spec.original_dir.clone(), upper_dir).clear();
+
+        let removing = {
+            let entries = self.inner.0.lock().unwrap();
+            entries.get(name).map(|entry| entry.removing) != Some(false)
+        };
+        let shutdown = Shutdown::new();
+        let spawned = cleared.and_then(|()| {
+            if removing {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is unmounted while resetting", name),
+                ))
+            } else {
+                let args = spec
+                    .options
+                    .to_args()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                self.spawn(name, &spec, config, shutdown.clone(), &args)
+            }
+        });
+
+        let mut entries = self.inner.0.lock().unwrap();
+        match (spawned, entries.get_mut(name)) {
+            (Ok(()), Some(entry)) => {
+                entry.shutdown = shutdown;
+                entry.resetting = false;
+                info!(self.logger, "reset: {}", name);
+                self.unmount_if_removed(name, entry);
+            }
+            (result, _) => {
+                if let Err(error) = result {
+                    error!(self.logger, "Cannot reset {}: {}", name, error);
+                }
+                entries.remove(name);
+            }
+        }
+        self.inner.1.notify_all();
+    }
+
+    /// Unmounts the mount. It is removed when the session ends.
+    pub fn remove(&self, name: &str) -> Result<(), io::Error> {
+        let mut entries = self.inner.0.lock().unwrap();
+        match entries.get_mut(name) {
+            Some(entry) => unmount(entry),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not mounted", name),
+            )),
+        }
+    }
+
+    /// Unmounts the all mounts (e.g., at the shutdown).
+    pub fn remove_all(&self) {
+        let mut entries = self.inner.0.lock().unwrap();
+        for (name, entry) in entries.iter_mut() {
+            if let Err(error) = unmount(entry) {
+                error!(self.logger, "Cannot unmount {}: {}", name, error);
+            }
+        }
+    }
+
+    /// Waits until no mounts remain.
+    pub fn wait(&self) {
+        let mut entries = self.inner.0.lock().unwrap();
+        while !entries.is_empty() {
+            entries = self.inner.1.wait(entries).unwrap();
+        }
+    }
+
+    pub fn config(&self, name: &str) -> Option<Arc<AtomicImmut<Config>>> {
+        let entries = self.inner.0.lock().unwrap();
+        entries
+            .get(name)
+            .filter(|entry| !entry.mounting)
+            .map(|entry| Arc::clone(&entry.config))
+    }
+
+    /// Returns the specs of the mounts with their current configs.
+    pub fn specs(&self) -> BTreeMap<String, MountSpec> {
+        let entries = self.inner.0.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, entry)| !entry.mounting)
+            .map(|(name, entry)| {
+                let spec = MountSpec {
+                    config: Some((*entry.config.load()).clone()),
+                    ..entry.spec.clone()
+                };
+                (name.clone(), spec)
+            })
+            .collect()
+    }
+
+    fn finish(&self, name: &str) {
+        let mut entries = self.inner.0.lock().unwrap();
+        // The resetting mount is mounted again.
+        let (spec, config) = match entries.get(name) {
+            Some(entry) if entry.resetting => (entry.spec.clone(), Arc::clone(&entry.config)),
+            _ => {
+                entries.remove(name);
+                self.inner.1.notify_all();
+                return;
+            }
+        };
+        drop(entries);
+
+        self.remount(name, spec, config);
+    }
+
+    fn ensure_not_stale(&self, mountpoint: &Path) -> Result<(), io::Error> {
+        if !shutdown::is_stale_mount(mountpoint) {
+            Ok(())
+        } else if self.force_unmount_stale {
+            warn!(
+                self.logger,
+                "unmounting the stale mountpoint: {:?}", mountpoint
+            );
+            shutdown::unmount(mountpoint)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{:?} is left mounted by the previous process (see --force-unmount-stale)",
+                    mountpoint
+                ),
+            ))
+        }
+    }
+}
+
+fn unmount(entry: &mut Entry) -> Result<(), io::Error> {
+    if entry.removing {
+        return Ok(());
+    }
+    // The resetting mount has been unmounted already. It is not mounted again.
+    // The mount being mounted is unmounted once it is mounted.
+    if entry.resetting || entry.mounting {
+        entry.removing = true;
+        return Ok(());
+    }
+    shutdown::unmount(&entry.spec.mountpoint)?;
+    // The sleeping requests are released after unmounting not to accept new ones.
+    entry.shutdown.request();
+    entry.removing = true;
+    Ok(())
+}
+
+/// The names are used in the paths of the HTTP API and the labels of the metrics.
+fn validate_name(name: &str) -> Result<(), io::Error> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid mount name: {:?}", name),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name(DEFAULT_MOUNT).is_ok());
+        assert!(validate_name("wal-0_a").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("data/wal").is_err());
+        assert!(validate_name("..").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! The overlay mode which keeps the original directory (the lower layer) unmodified.
+//!
+//! The files created or modified on the mountpoint are written to the upper directory.
+//! The removed files of the lower layer are hidden by the whiteouts (`.wh.{name}` in the upper directory),
+//! and the directories recreated over them are marked opaque (`.wh..wh..opq`) to hide their old contents.
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    lower: PathBuf,
+    upper: PathBuf,
+}
+
+impl Overlay {
+    pub fn new(lower: PathBuf, upper: PathBuf) -> Overlay {
+        Overlay { lower, upper }
+    }
+
+    /// Creates the upper directory if it does not exist.
+    /// The upper directory must not overlap the lower one nor the mountpoint.
+    pub fn open(lower: &Path, upper: &Path, mountpoint: &Path) -> Result<Overlay, io::Error> {
+        fs::create_dir_all(upper)?;
+        let lower = fs::canonicalize(lower)?;
+        let upper = fs::canonicalize(upper)?;
+        if upper.starts_with(&lower) || lower.starts_with(&upper) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The upper directory overlaps the original directory",
+            ));
+        }
+        let mountpoint = canonicalize_parent(mountpoint)?;
+        if upper.starts_with(&mountpoint) || mountpoint.starts_with(&upper) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The upper directory overlaps the mountpoint",
+            ));
+        }
+        Ok(Overlay { lower, upper })
+    }
+
+    pub fn upper(&self) -> &Path {
+        &self.upper
+    }
+
+    /// Returns the path relative to the layer which has the path.
+    pub fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.upper)
+            .or_else(|_| path.strip_prefix(&self.lower))
+            .unwrap_or(path)
+    }
+
+    pub fn upper_path(&self, path: &Path) -> PathBuf {
+        self.upper.join(self.relative(path))
+    }
+
+    pub fn lower_path(&self, path: &Path) -> PathBuf {
+        self.lower.join(self.relative(path))
+    }
+
+    pub fn is_lower(&self, path: &Path) -> bool {
+        path.starts_with(&self.lower) && !path.starts_with(&self.upper)
+    }
+
+    /// Returns the path of the file which exists on the mountpoint,
+    /// or the path in the upper directory to create it.
+    pub fn locate(&self, path: &Path, exists: bool) -> PathBuf {
+        let upper = self.upper_path(path);
+        if !exists || upper.symlink_metadata().is_ok() {
+            upper
+        } else {
+            self.lower_path(path)
+        }
+    }
+
+    /// Returns whether the file exists in the lower layer and is shown on the mountpoint.
+    pub fn lower_visible(&self, path: &Path) -> bool {
+        if self.lower_path(path).symlink_metadata().is_err() {
+            return false;
+        }
+
+        let mut upper = self.upper.clone();
+        for component in self.relative(path).components() {
+            let name = component.as_os_str();
+            if upper.join(OPAQUE).symlink_metadata().is_ok()
+                || upper.join(whiteout_name(name)).symlink_metadata().is_ok()
+            {
+                return false;
+            }
+
+            upper.push(name);
+            match upper.symlink_metadata() {
+                Ok(metadata) if !metadata.is_dir() => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Returns whether the directory shows the files of the lower layer,
+    /// i.e., it is neither hidden nor recreated over the removed one (opaque).
+    pub fn merges_lower(&self, dir: &Path) -> bool {
+        self.lower_visible(dir)
+            && self
+                .upper_path(dir)
+                .join(OPAQUE)
+                .symlink_metadata()
+                .is_err()
+    }
+
+    /// Lists the files in the directory merging the layers.
+    /// The files in the upper directory take precedence.
+    pub fn read_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        let upper = self.upper_path(dir);
+        let lower = self.lower_path(dir);
+
+        let mut paths = Vec::new();
+        let mut hidden = HashSet::new();
+        let mut opaque = false;
+
+        if is_dir(&upper) {
+            for entry in fs::read_dir(&upper)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if name == OPAQUE {
+                    opaque = true;
+                } else if let Some(target) = whiteout_target(&name) {
+                    hidden.insert(target.to_os_string());
+                } else {
+                    hidden.insert(name);
+                    paths.push(entry.path());
+                }
+            }
+        }
+
+        if !opaque && is_dir(&lower) && self.lower_visible(&lower) {
+            for entry in fs::read_dir(&lower)? {
+                let entry = entry?;
+                if !hidden.contains(&entry.file_name()) {
+                    paths.push(entry.path());
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the latest modification time of the directory in the layers.
+    pub fn modified(&self, dir: &Path) -> Result<SystemTime, io::Error> {
+        let upper = fs::metadata(self.upper_path(dir)).and_then(|m| m.modified());
+        let lower = fs::metadata(self.lower_path(dir)).and_then(|m| m.modified());
+        match (upper, lower) {
+            (Ok(upper), Ok(lower)) => Ok(upper.max(lower)),
+            (Ok(time), Err(_)) | (Err(_), Ok(time)) => Ok(time),
+            (Err(error), Err(_)) => Err(error),
+        }
+    }
+
+    /// Creates the missing parent directories of the path in the upper directory
+    /// with the permissions of the lower ones.
+    /// Returns the pairs of the lower and the upper paths of the created directories.
+    pub fn create_parents(&self, path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, io::Error> {
+        let mut created = Vec::new();
+        let mut upper = self.upper.clone();
+        let mut lower = self.lower.clone();
+
+        let relative = self.relative(path);
+        if let Some(parent) = relative.parent() {
+            for component in parent.components() {
+                upper.push(component);
+                lower.push(component);
+                if upper.symlink_metadata().is_ok() {
+                    continue;
+                }
+
+                let mode = fs::metadata(&lower).map(|m| m.mode()).unwrap_or(0o755);
+                fs::DirBuilder::new().mode(mode & 0o7777).create(&upper)?;
+                created.push((lower.clone(), upper.clone()));
+            }
+        }
+        Ok(created)
+    }
+
+    /// Copies the file in the lower layer to the upper directory.
+    /// The contents of the directories are not copied.
+    pub fn copy_up(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        let lower = self.lower_path(path);
+        let upper = self.upper_path(path);
+        let metadata = lower.symlink_metadata()?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_file() {
+            fs::copy(&lower, &upper)?;
+        } else if file_type.is_dir() {
+            fs::DirBuilder::new()
+                .mode(metadata.mode() & 0o7777)
+                .create(&upper)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&lower)?, &upper)?;
+        } else {
+            let cpath = CString::new(upper.as_os_str().as_bytes())?;
+            let mode = metadata.mode() as libc::mode_t;
+            if unsafe { libc::mknod(cpath.as_ptr(), mode, metadata.rdev() as libc::dev_t) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Opening the special files may block.
+        if file_type.is_file() || file_type.is_dir() {
+            let times = fs::FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?);
+            fs::File::open(&upper)?.set_times(times)?;
+        }
+        // Only the privileged users can keep the owner.
+        let _ = std::os::unix::fs::lchown(&upper, Some(metadata.uid()), Some(metadata.gid()));
+
+        Ok(upper)
+    }
+
+    /// Hides the file in the lower layer if it is shown.
+    /// The parent directory in the upper directory must exist.
+    pub fn whiteout(&self, path: &Path) -> Result<(), io::Error> {
+        let lower = self.lower_path(path);
+        if !self.lower_visible(&lower) {
+            return Ok(());
+        }
+
+        let upper = self.upper_path(path);
+        match upper.file_name() {
+            Some(name) => fs::File::create(upper.with_file_name(whiteout_name(name))).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the whiteout of the file to create it.
+    /// Returns whether the file had been removed.
+    pub fn remove_whiteout(&self, path: &Path) -> Result<bool, io::Error> {
+        let upper = self.upper_path(path);
+        let name = match upper.file_name() {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+
+        match fs::remove_file(upper.with_file_name(whiteout_name(name))) {
+            Ok(()) => Ok(true),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Hides the contents of the directory in the lower layer.
+    pub fn make_opaque(&self, dir: &Path) -> Result<(), io::Error> {
+        fs::File::create(self.upper_path(dir).join(OPAQUE)).map(|_| ())
+    }
+
+    /// Removes the all files in the upper directory.
+    pub fn clear(&self) -> Result<(), io::Error> {
+        for entry in fs::read_dir(&self.upper)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files of these names are used by the overlay mode, so cannot be created on the mountpoint.
+pub fn is_reserved_name(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    whiteout
+}
+
+fn whiteout_target(name: &OsStr) -> Option<&OsStr> {
+    name.as_bytes()
+        .strip_prefix(WHITEOUT_PREFIX.as_bytes())
+        .map(OsStr::from_bytes)
+}
+
+fn is_dir(path: &Path) -> bool {
+    path.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Resolves the path without accessing the file itself,
+/// which may be the mountpoint being mounted (or left by the previous process).
+fn canonicalize_parent(path: &Path) -> Result<PathBuf, io::Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent.as_os_str().is_empty() => {
+            Ok(fs::canonicalize(".")?.join(name))
+        }
+        (Some(parent), Some(name)) => Ok(fs::canonicalize(parent)?.join(name)),
+        _ => fs::canonicalize(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(paths: Vec<PathBuf>) -> Vec<OsString> {
+        let mut names = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_os_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_overlay() {
+        let root = std::env::temp_dir().join(format!("mizumochi_overlay_{}", std::process::id()));
+        let (lower, upper) = (root.join("lower"), root.join("upper"));
+        fs::create_dir_all(lower.join("dir/sub")).unwrap();
+        fs::write(lower.join("a"), b"lower").unwrap();
+        fs::write(lower.join("dir/b"), b"lower").unwrap();
+        let mountpoint = root.join("mnt");
+        assert!(Overlay::open(&lower.join("dir"), &lower, &mountpoint).is_err());
+        assert!(Overlay::open(&lower, &mountpoint.join("upper"), &mountpoint).is_err());
+        assert!(Overlay::open(&lower, &root, &mountpoint).is_err());
+        let overlay = Overlay::open(&lower, &upper, &mountpoint).unwrap();
+        let (lower, upper) = (
+            fs::canonicalize(lower).unwrap(),
+            fs::canonicalize(upper).unwrap(),
+        );
+
+        assert_eq!(lower.join("a"), overlay.locate(&lower.join("a"), true));
+        assert_eq!(upper.join("c"), overlay.locate(&lower.join("c"), false));
+
+        // Copying up keeps the lower file.
+        let copied = overlay.copy_up(&lower.join("a")).unwrap();
+        fs::write(&copied, b"upper").unwrap();
+        assert_eq!(b"lower".to_vec(), fs::read(lower.join("a")).unwrap());
+        assert_eq!(upper.join("a"), overlay.locate(&lower.join("a"), true));
+        assert_eq!(vec!["a", "dir"], names(overlay.read_dir(&lower).unwrap()));
+
+        // The removed files are hidden by the whiteouts.
+        let created = overlay.create_parents(&lower.join("dir/b")).unwrap();
+        assert_eq!(vec![(lower.join("dir"), upper.join("dir"))], created);
+        overlay.whiteout(&lower.join("dir/b")).unwrap();
+        assert_eq!(
+            vec!["sub"],
+            names(overlay.read_dir(&lower.join("dir")).unwrap())
+        );
+        assert!(!overlay.lower_visible(&lower.join("dir/b")));
+
+        // The recreated directory hides the old contents.
+        overlay.whiteout(&lower.join("dir/sub")).unwrap();
+        assert!(overlay.read_dir(&lower.join("dir")).unwrap().is_empty());
+        assert!(overlay.remove_whiteout(&lower.join("dir/sub")).unwrap());
+        fs::create_dir(upper.join("dir/sub")).unwrap();
+        overlay.make_opaque(&upper.join("dir/sub")).unwrap();
+        fs::write(lower.join("dir/sub/c"), b"lower").unwrap();
+        assert!(overlay.read_dir(&lower.join("dir/sub")).unwrap().is_empty());
+        assert!(!overlay.merges_lower(&lower.join("dir/sub")));
+        assert!(overlay.merges_lower(&lower.join("dir")));
+
+        overlay.clear().unwrap();
+        assert!(fs::read_dir(&upper).unwrap().next().is_none());
+        assert_eq!(vec!["a", "dir"], names(overlay.read_dir(&lower).unwrap()));
+        assert_eq!(
+            vec!["b", "sub"],
+            names(overlay.read_dir(&lower.join("dir")).unwrap())
+        );
+
+        assert!(is_reserved_name(OsStr::new(".wh.a")));
+        assert!(!is_reserved_name(OsStr::new(".a")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Permission checking against the caller of the operations (see access(2)).
+use fuse::{FileAttr, FileType, Request};
+use std::cell::OnceCell;
+use std::fs;
+use std::os::raw::c_int;
+
+pub const R_OK: u32 = libc::R_OK as u32;
+pub const W_OK: u32 = libc::W_OK as u32;
+pub const X_OK: u32 = libc::X_OK as u32;
+
+/// `Caller` is the process which requests the operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    /// The supplementary groups are read only when the primary group does not match.
+    pub groups: OnceCell<Vec<u32>>,
+}
+
+impl Caller {
+    pub fn from_request(req: &Request) -> Caller {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+            groups: OnceCell::new(),
+        }
+    }
+
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid
+            || self
+                .groups
+                .get_or_init(|| supplementary_groups(self.pid))
+                .contains(&gid)
+    }
+
+    /// Returns `EACCES` unless the caller is permitted the `mask` (`R_OK`, `W_OK` and `X_OK`) on the file.
+    pub fn check(&self, attr: &FileAttr, mask: u32) -> Result<(), c_int> {
+        let mask = mask & (R_OK | W_OK | X_OK);
+
+        let permitted = if self.uid == 0 {
+            // The superuser can execute only the files executable by someone.
+            mask & X_OK == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0
+        } else {
+            let bits = if self.uid == attr.uid {
+                attr.perm >> 6
+            } else if self.in_group(attr.gid) {
+                attr.perm >> 3
+            } else {
+                attr.perm
+            };
+            u32::from(bits & 0o7) & mask == mask
+        };
+
+        if permitted {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+}
+
+/// Returns the permissions required to open a file with the flags.
+pub fn mask_from_open_flags(flags: u32) -> u32 {
+    let mask = match flags as c_int & libc::O_ACCMODE {
+        libc::O_WRONLY => W_OK,
+        libc::O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    };
+    if flags as c_int & libc::O_TRUNC != 0 {
+        mask | W_OK
+    } else {
+        mask
+    }
+}
+
+/// Reads the supplementary groups of the process from procfs.
+/// Returns nothing if they are unavailable (e.g., on macOS).
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with("Groups:"))
+                .map(|line| {
+                    line["Groups:".len()..]
+                        .split_whitespace()
+                        .filter_map(|gid| gid.parse().ok())
+                        .collect()
+                })
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Timespec;
+
+    fn attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        let t = Timespec::new(0, 0);
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: t,
+            mtime: t,
+            ctime: t,
+            crtime: t,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn caller(uid: u32, gid: u32, groups: Vec<u32>) -> Caller {
+        Caller {
+            uid,
+            gid,
+            pid: 0,
+            groups: OnceCell::from(groups),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let file = attr(FileType::RegularFile, 0o640, 1000, 100);
+
+        assert_eq!(caller(1000, 1000, vec![]).check(&file, R_OK | W_OK), Ok(()));
+        assert_eq!(
+            caller(1000, 1000, vec![]).check(&file, X_OK),
+            Err(libc::EACCES)
+        );
+        assert_eq!(caller(1001, 100, vec![]).check(&file, R_OK), Ok(()));
+        assert_eq!(caller(1001, 1001, vec![100]).check(&file, R_OK), Ok(()));
+        assert_eq!(
+            caller(1001, 1001, vec![100]).check(&file, W_OK),
+            Err(libc::EACCES)
+        );
+        assert_eq!(
+            caller(1002, 1002, vec![]).check(&file, R_OK),
+            Err(libc::EACCES)
+        );
+        // `F_OK` only checks the existence.
+        assert_eq!(caller(1002, 1002, vec![]).check(&file, 0), Ok(()));
+
+        assert_eq!(caller(0, 0, vec![]).check(&file, R_OK | W_OK), Ok(()));
+        assert_eq!(caller(0, 0, vec![]).check(&file, X_OK), Err(libc::EACCES));
+        let dir = attr(FileType::Directory, 0o700, 1000, 100);
+        assert_eq!(caller(0, 0, vec![]).check(&dir, X_OK), Ok(()));
+    }
+
+    #[test]
+    fn test_in_group() {
+        let caller = Caller {
+            uid: 1000,
+            gid: 100,
+            pid: std::process::id(),
+            groups: OnceCell::new(),
+        };
+
+        // The supplementary groups are not needed for the primary group.
+        assert!(caller.in_group(100));
+        assert!(caller.groups.get().is_none());
+
+        caller.in_group(101);
+        assert_eq!(
+            Some(&supplementary_groups(std::process::id())),
+            caller.groups.get()
+        );
+    }
+
+    #[test]
+    fn test_mask_from_open_flags() {
+        assert_eq!(mask_from_open_flags(libc::O_RDONLY as u32), R_OK);
+        assert_eq!(mask_from_open_flags(libc::O_WRONLY as u32), W_OK);
+        assert_eq!(mask_from_open_flags(libc::O_RDWR as u32), R_OK | W_OK);
+        assert_eq!(
+            mask_from_open_flags((libc::O_RDONLY | libc::O_TRUNC) as u32),
+            R_OK | W_OK
+        );
+    }
+}
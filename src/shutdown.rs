@@ -0,0 +1,220 @@
+//! Graceful shutdown on the signals (SIGINT, SIGTERM and SIGHUP).
+use crate::mounts::Mounts;
+use slog::Logger;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io;
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{self, Command};
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+#[derive(Debug, Default)]
+struct State {
+    requested: bool,
+    failed: bool,
+    // The signal which requested the shutdown.
+    signal: Option<libc::c_int>,
+}
+
+/// `Shutdown` is shared by the threads to know whether the shutdown is requested.
+/// Each mount has its own one to release the sleeping requests when it is unmounted.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Returns whether the shutdown was requested because of an error.
+    pub fn is_failed(&self) -> bool {
+        self.inner.0.lock().unwrap().failed
+    }
+
+    /// Sleeps for the duration unless the shutdown is requested in the meantime,
+    /// so that the throttled or delayed requests are released at the shutdown.
+    pub fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        let mut state = self.inner.0.lock().unwrap();
+        while !state.requested {
+            let now = Instant::now();
+            if deadline <= now {
+                break;
+            }
+            state = self.inner.1.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn request(&self) {
+        self.inner.0.lock().unwrap().requested = true;
+        self.inner.1.notify_all();
+    }
+
+    /// Waits until the shutdown is requested.
+    pub fn wait(&self) {
+        let mut state = self.inner.0.lock().unwrap();
+        while !state.requested {
+            state = self.inner.1.wait(state).unwrap();
+        }
+    }
+
+    /// Requests the shutdown because of the signal.
+    fn request_by_signal(&self, signal: libc::c_int) {
+        self.inner.0.lock().unwrap().signal = Some(signal);
+        self.request();
+    }
+
+    /// Returns the signal which requested the shutdown.
+    pub fn signal(&self) -> Option<libc::c_int> {
+        self.inner.0.lock().unwrap().signal
+    }
+
+    /// Requests the shutdown because of an error (e.g., the HTTP server stopped).
+    pub fn fail(&self) {
+        self.inner.0.lock().unwrap().failed = true;
+        // The signal is handled by the thread spawned by `spawn_signal_handler`.
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGTERM);
+        }
+    }
+}
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in &SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+/// Blocks the signals in the calling thread and the threads spawned after this.
+/// It must be called before spawning any thread.
+pub fn block_signals() -> Result<(), io::Error> {
+    let set = signal_set();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Spawns the thread which waits for the signals.
+/// The first signal requests the shutdown and unmounts the all mounts,
+/// which ends the FUSE sessions after the open files are closed.
+/// The second signal terminates the process immediately.
+pub fn spawn_signal_handler(logger: Logger, shutdown: Shutdown, mounts: Mounts) {
+    thread::spawn(move || {
+        let set = signal_set();
+        let mut signal = 0;
+
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            error!(logger, "Cannot wait for signals");
+            return;
+        }
+        info!(logger, "Shutting down by signal {}", signal);
+        shutdown.request_by_signal(signal);
+        mounts.remove_all();
+
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            warn!(logger, "Terminated by signal {}", signal);
+            process::exit(128 + signal);
+        }
+    });
+}
+
+/// Returns whether the mountpoint is left by a FUSE file system which has exited
+/// (i.e., it fails with "Transport endpoint is not connected").
+pub fn is_stale_mount(mountpoint: &Path) -> bool {
+    match mountpoint.metadata() {
+        Err(error) => error.raw_os_error() == Some(libc::ENOTCONN),
+        Ok(_) => false,
+    }
+}
+
+/// Unmounts the mountpoint lazily so that the files kept open do not block it.
+pub fn unmount(mountpoint: &Path) -> Result<(), io::Error> {
+    unmount_with(mountpoint, true)
+}
+
+/// Unmounts the mountpoint unless the files on it are in use.
+pub fn unmount_unused(mountpoint: &Path) -> Result<(), io::Error> {
+    unmount_with(mountpoint, false)
+}
+
+fn unmount_with(mountpoint: &Path, lazy: bool) -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+        if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 {
+            return Ok(());
+        }
+    }
+
+    // The users other than root unmount via fusermount.
+    let mut command = if cfg!(target_os = "linux") {
+        let mut command = Command::new("fusermount");
+        command.arg("-u");
+        if lazy {
+            command.arg("-z");
+        }
+        command
+    } else {
+        let mut command = Command::new("umount");
+        if lazy {
+            command.arg("-f");
+        }
+        command
+    };
+    let status = command.arg(mountpoint).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("unmount exited with {}", status)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep() {
+        let shutdown = Shutdown::new();
+
+        let start = Instant::now();
+        shutdown.sleep(Duration::from_millis(10));
+        assert!(Duration::from_millis(10) <= start.elapsed());
+
+        // The sleeping threads are released by the shutdown.
+        let s = shutdown.clone();
+        let sleeper = thread::spawn(move || s.sleep(Duration::from_secs(60)));
+        shutdown.request();
+        sleeper.join().unwrap();
+        assert!(!shutdown.is_failed());
+        assert_eq!(None, shutdown.signal());
+    }
+
+    #[test]
+    fn test_request_by_signal() {
+        let shutdown = Shutdown::new();
+        shutdown.request_by_signal(libc::SIGTERM);
+        assert_eq!(Some(libc::SIGTERM), shutdown.signal());
+
+        // The threads are not blocked after the shutdown.
+        shutdown.sleep(Duration::from_secs(60));
+        shutdown.wait();
+    }
+}
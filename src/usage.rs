@@ -0,0 +1,172 @@
+//! The disk usage of the original directory counted against the virtual capacity.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `DiskUsage` computes the usage in the background not to block the requests,
+/// and computes it again once the interval passes to count the files changed by the others.
+///
+/// The changes by mizumochi itself are added in the meantime,
+/// so the usage is approximate while it is computed again.
+#[derive(Debug)]
+pub struct DiskUsage {
+    interval: Duration,
+    used: Option<u64>,
+    computed_at: Option<Instant>,
+    computing: Option<Receiver<Result<u64, io::Error>>>,
+}
+
+impl DiskUsage {
+    pub fn new(interval: Duration) -> DiskUsage {
+        DiskUsage {
+            interval,
+            used: None,
+            computed_at: None,
+            computing: None,
+        }
+    }
+
+    /// Returns the usage, or `None` until it is computed at first.
+    /// `compute` is called in another thread if the usage is outdated.
+    pub fn get<F>(&mut self, compute: F) -> Result<Option<u64>, io::Error>
+    where
+        F: FnOnce() -> Result<u64, io::Error> + Send + 'static,
+    {
+        if let Some(ref computing) = self.computing {
+            match computing.try_recv() {
+                Ok(result) => {
+                    self.computing = None;
+                    self.computed_at = Some(Instant::now());
+                    self.used = Some(result?);
+                }
+                Err(TryRecvError::Empty) => return Ok(self.used),
+                Err(TryRecvError::Disconnected) => self.computing = None,
+            }
+        }
+
+        let outdated = match self.computed_at {
+            Some(computed_at) => self.interval <= computed_at.elapsed(),
+            None => true,
+        };
+        if outdated {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = tx.send(compute());
+            });
+            self.computing = Some(rx);
+        }
+
+        Ok(self.used)
+    }
+
+    /// Records the bytes newly used by the written data.
+    pub fn consume(&mut self, growth: u64) {
+        if let Some(ref mut used) = self.used {
+            *used += growth;
+        }
+    }
+
+    /// Records the bytes freed by removing files.
+    pub fn release(&mut self, size: u64) {
+        if let Some(ref mut used) = self.used {
+            *used = used.saturating_sub(size);
+        }
+    }
+}
+
+/// Sums up the size of the regular files under the given directory.
+/// `list_dir` returns the paths of the files in each directory.
+pub fn compute_disk_usage<F>(dir: &Path, list_dir: &F) -> Result<u64, io::Error>
+where
+    F: Fn(&Path) -> Result<Vec<PathBuf>, io::Error>,
+{
+    let mut used = 0;
+    for path in list_dir(dir)? {
+        // Do not follow symbolic links.
+        let metadata = fs::symlink_metadata(&path)?;
+
+        if metadata.is_dir() {
+            used += compute_disk_usage(&path, list_dir)?;
+        } else if metadata.is_file() {
+            used += metadata.len();
+        }
+    }
+
+    Ok(used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Waits for the usage computed in the background.
+    fn wait(usage: &mut DiskUsage, used: u64) -> Option<u64> {
+        for _ in 0..1000 {
+            match usage.get(move || Ok(used)).unwrap() {
+                Some(used) => return Some(used),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let mut usage = DiskUsage::new(Duration::from_secs(3600));
+        assert_eq!(Some(10), wait(&mut usage, 10));
+
+        usage.consume(5);
+        usage.release(3);
+        assert_eq!(Some(12), usage.get(|| Ok(100)).unwrap());
+        usage.release(100);
+        assert_eq!(Some(0), usage.get(|| Ok(100)).unwrap());
+
+        // The usage is computed again once the interval passes.
+        let mut usage = DiskUsage::new(Duration::from_secs(0));
+        assert_eq!(Some(10), wait(&mut usage, 10));
+        thread::sleep(Duration::from_millis(10));
+        let mut used = Some(10);
+        for _ in 0..1000 {
+            used = usage.get(|| Ok(20)).unwrap();
+            if used == Some(20) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(Some(20), used);
+
+        // The errors are reported.
+        let mut usage = DiskUsage::new(Duration::from_secs(0));
+        usage.get(|| Err(io::Error::other("error"))).unwrap();
+        let mut result = Ok(None);
+        for _ in 0..1000 {
+            result = usage.get(|| Err(io::Error::other("error")));
+            if result.is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_compute_disk_usage() {
+        let root = std::env::temp_dir().join(format!("mizumochi_usage_{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("a"), b"abc").unwrap();
+        fs::write(root.join("dir/b"), b"de").unwrap();
+        std::os::unix::fs::symlink("a", root.join("link")).unwrap();
+
+        let list_dir = |dir: &Path| {
+            fs::read_dir(dir)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect()
+        };
+        assert_eq!(5, compute_disk_usage(&root, &list_dir).unwrap());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! Extended attributes of the files without following symbolic links.
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::raw::c_int;
+use std::path::Path;
+
+/// Reads the value into the buffer and returns its size.
+/// Only the size is returned if the buffer is empty.
+pub fn get(path: &Path, name: &OsStr, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let path = cstring_from(path.as_os_str())?;
+    let name = cstring_from(name)?;
+
+    imp::get(&path, &name, buffer)
+}
+
+pub fn set(path: &Path, name: &OsStr, value: &[u8], flags: c_int) -> Result<(), io::Error> {
+    let path = cstring_from(path.as_os_str())?;
+    let name = cstring_from(name)?;
+
+    imp::set(&path, &name, value, flags)
+}
+
+/// Reads the null-terminated names into the buffer and returns their size.
+/// Only the size is returned if the buffer is empty.
+pub fn list(path: &Path, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let path = cstring_from(path.as_os_str())?;
+
+    imp::list(&path, buffer)
+}
+
+pub fn remove(path: &Path, name: &OsStr) -> Result<(), io::Error> {
+    let path = cstring_from(path.as_os_str())?;
+    let name = cstring_from(name)?;
+
+    imp::remove(&path, &name)
+}
+
+fn cstring_from(s: &OsStr) -> Result<CString, io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(s.as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use libc::c_void;
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::raw::c_int;
+
+    pub fn get(path: &CStr, name: &CStr, buffer: &mut [u8]) -> Result<usize, io::Error> {
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len(),
+            )
+        };
+        result_from(size)
+    }
+
+    pub fn set(path: &CStr, name: &CStr, value: &[u8], flags: c_int) -> Result<(), io::Error> {
+        let ret = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                flags,
+            )
+        };
+        result_from(ret as isize).map(|_| ())
+    }
+
+    pub fn list(path: &CStr, buffer: &mut [u8]) -> Result<usize, io::Error> {
+        let size =
+            unsafe { libc::llistxattr(path.as_ptr(), buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        result_from(size)
+    }
+
+    pub fn remove(path: &CStr, name: &CStr) -> Result<(), io::Error> {
+        let ret = unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+        result_from(ret as isize).map(|_| ())
+    }
+
+    fn result_from(ret: isize) -> Result<usize, io::Error> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+// TODO: support the extended attributes on macOS.
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::raw::c_int;
+
+    pub fn get(_: &CStr, _: &CStr, _: &mut [u8]) -> Result<usize, io::Error> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    pub fn set(_: &CStr, _: &CStr, _: &[u8], _: c_int) -> Result<(), io::Error> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    pub fn list(_: &CStr, _: &mut [u8]) -> Result<usize, io::Error> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    pub fn remove(_: &CStr, _: &CStr) -> Result<(), io::Error> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_xattr() {
+        let path = std::env::temp_dir().join(format!("mizumochi_xattr_{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let name = OsStr::new("user.mizumochi");
+
+        match set(&path, name, b"value", 0) {
+            // The extended attributes may be unavailable on the file system of the temporary directory.
+            Err(ref error) if error.raw_os_error() == Some(libc::ENOTSUP) => {
+                fs::remove_file(&path).unwrap();
+                return;
+            }
+            result => result.unwrap(),
+        }
+
+        assert_eq!(get(&path, name, &mut []).unwrap(), 5);
+        let mut buffer = [0; 5];
+        assert_eq!(get(&path, name, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer, b"value");
+        let mut buffer = [0; 2];
+        assert_eq!(
+            get(&path, name, &mut buffer).unwrap_err().raw_os_error(),
+            Some(libc::ERANGE)
+        );
+
+        let mut buffer = vec![0; list(&path, &mut []).unwrap()];
+        let len = list(&path, &mut buffer).unwrap();
+        assert!(buffer[..len]
+            .split(|&c| c == 0)
+            .any(|n| n == b"user.mizumochi"));
+
+        remove(&path, name).unwrap();
+        assert_eq!(
+            get(&path, name, &mut []).unwrap_err().raw_os_error(),
+            Some(libc::ENODATA)
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}